@@ -0,0 +1,222 @@
+use std::path::Path;
+
+use image::imageops::resize;
+use image::{DynamicImage, ImageBuffer, Luma, Rgb, Rgba};
+
+use crate::{get_scaled_input, open_image, MatchData};
+
+/// What transparent parts of photos and targets are shown over, and the
+/// default `MosaicConfig::background`.
+pub const BACKGROUND: Rgb<u8> = Rgb {
+    data: [255, 255, 255],
+};
+
+fn has_alpha(img: &DynamicImage) -> bool {
+    match img {
+        DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) => true,
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_) => false,
+    }
+}
+
+fn blend(colour: Rgb<u8>, background: Rgb<u8>, alpha: u8) -> Rgb<u8> {
+    let alpha = alpha as u32;
+    let mut blended = background;
+    for (value, &colour) in blended.data.iter_mut().zip(colour.data.iter()) {
+        *value = ((colour as u32 * alpha + *value as u32 * (255 - alpha) + 127) / 255) as u8;
+    }
+    blended
+}
+
+/// The alpha channel of a target, 0 where it is transparent.
+pub type Alpha = ImageBuffer<Luma<u8>, Vec<u8>>;
+
+/// Returns `img` without its alpha channel, shown over `background` where it
+/// is transparent rather than over whatever colour the hidden pixels have.
+pub fn flatten(img: &DynamicImage, background: Rgb<u8>) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    if !has_alpha(img) {
+        return img.to_rgb();
+    }
+
+    let rgba = img.to_rgba();
+    ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).data;
+        blend(Rgb([r, g, b]), background, a)
+    })
+}
+
+/// Returns the alpha channel of `img`, or `None` if it is opaque throughout.
+pub fn get_alpha(img: &DynamicImage) -> Option<Alpha> {
+    if !has_alpha(img) {
+        return None;
+    }
+
+    let rgba = img.to_rgba();
+    if rgba.pixels().all(|pixel| pixel.data[3] == 255) {
+        return None;
+    }
+    Some(ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+        Luma([rgba.get_pixel(x, y).data[3]])
+    }))
+}
+
+/// A photo to make a mosaic of, flattened over `BACKGROUND`, and its alpha
+/// channel if it has any transparency.
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub image: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    pub alpha: Option<Alpha>,
+    /// Where tiles may go, the same size as `image`, if not wherever the
+    /// target is opaque.
+    pub mask: Option<Alpha>,
+}
+
+impl Target {
+    pub fn new(img: &DynamicImage) -> Self {
+        Target {
+            image: flatten(img, BACKGROUND),
+            alpha: get_alpha(img),
+            mask: None,
+        }
+    }
+
+    /// Only lets tiles go where `mask` is set, see `open_mask`, scaling it to
+    /// the target.
+    pub fn set_mask(&mut self, mask: &Alpha) {
+        self.mask = Some(get_scaled_alpha(
+            mask,
+            self.image.width(),
+            self.image.height(),
+        ));
+    }
+
+    /// The mask cells are laid out in, see `get_visible_cells`: the one set
+    /// with `set_mask`, otherwise the target's alpha channel.
+    pub fn get_mask(&self) -> Option<&Alpha> {
+        self.mask.as_ref().or(self.alpha.as_ref())
+    }
+
+    /// Resizes the target to the size of the mosaic made from it, see
+    /// `get_scaled_input`.
+    pub fn get_scaled(&self, scale: f64) -> Target {
+        let image = get_scaled_input(&self.image, scale);
+        let (width, height) = image.dimensions();
+        let alpha = self
+            .alpha
+            .as_ref()
+            .map(|alpha| get_scaled_alpha(alpha, width, height));
+        let mask = self
+            .mask
+            .as_ref()
+            .map(|mask| get_scaled_alpha(mask, width, height));
+        Target { image, alpha, mask }
+    }
+}
+
+/// Opens the target at `path`, see `open_image`.
+pub fn open_target(path: &Path) -> image::ImageResult<Target> {
+    Ok(Target::new(&open_image(path)?))
+}
+
+/// Opens a mask image: where it is transparent if it has any transparency,
+/// otherwise where it is dark, is left without tiles.
+pub fn open_mask(path: &Path) -> image::ImageResult<Alpha> {
+    let img = open_image(path)?;
+    Ok(get_alpha(&img).unwrap_or_else(|| img.to_luma()))
+}
+
+/// Resizes the alpha channel of a target to `width` x `height`.
+pub fn get_scaled_alpha(alpha: &Alpha, width: u32, height: u32) -> Alpha {
+    if alpha.dimensions() == (width, height) {
+        return alpha.clone();
+    }
+
+    resize(alpha, width, height, image::FilterType::Triangle)
+}
+
+/// Returns how much of the `width` x `height` region of `alpha` at `x`, `y`
+/// is set, from 0.0 where it is wholly transparent to 1.0 where it is
+/// wholly opaque.
+pub fn get_opacity(alpha: &Alpha, x: u32, y: u32, width: u32, height: u32) -> f64 {
+    let mut sum = 0u64;
+    for j in y..y + height {
+        for i in x..x + width {
+            sum += alpha.get_pixel(i, j).data[0] as u64;
+        }
+    }
+    sum as f64 / ((width * height).max(1) as f64 * 255.0)
+}
+
+/// Returns the alpha channel of a mosaic: the target's `alpha`, or opaque if
+/// it has none, under the tiles and transparent everywhere else. `None` if
+/// that is opaque throughout.
+pub fn get_mosaic_alpha(
+    width: u32,
+    height: u32,
+    match_data: &[MatchData],
+    alpha: Option<&Alpha>,
+) -> Option<Alpha> {
+    let mut mosaic_alpha = ImageBuffer::new(width, height);
+    for m in match_data.iter() {
+        for y in m.y..m.y + m.tile.height() {
+            for x in m.x..m.x + m.tile.width() {
+                let value = alpha.map_or(255, |alpha| alpha.get_pixel(x, y).data[0]);
+                mosaic_alpha.put_pixel(x, y, Luma([value]));
+            }
+        }
+    }
+
+    if mosaic_alpha.pixels().all(|pixel| pixel.data[0] == 255) {
+        None
+    } else {
+        Some(mosaic_alpha)
+    }
+}
+
+/// Fades `output` out to `background` wherever `alpha` is transparent.
+pub fn fade_out(
+    output: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    alpha: &Alpha,
+    background: Rgb<u8>,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    ImageBuffer::from_fn(output.width(), output.height(), |x, y| {
+        blend(
+            *output.get_pixel(x, y),
+            background,
+            alpha.get_pixel(x, y).data[0],
+        )
+    })
+}
+
+/// Gives `output` the alpha channel `alpha`, or makes it opaque throughout if
+/// there is none.
+pub fn add_alpha(
+    output: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    alpha: Option<&Alpha>,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    ImageBuffer::from_fn(output.width(), output.height(), |x, y| {
+        let [r, g, b] = output.get_pixel(x, y).data;
+        let a = alpha.map_or(255, |alpha| alpha.get_pixel(x, y).data[0]);
+        Rgba([r, g, b, a])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opacity() {
+        // Opaque on the left half, transparent on the right.
+        let alpha = Alpha::from_fn(4, 4, |x, _| Luma([if x < 2 { 255 } else { 0 }]));
+        assert_eq!(get_opacity(&alpha, 0, 0, 4, 4), 0.5);
+        assert_eq!(get_opacity(&alpha, 0, 0, 2, 4), 1.0);
+        assert_eq!(get_opacity(&alpha, 2, 1, 2, 3), 0.0);
+        assert_eq!(get_opacity(&alpha, 1, 0, 2, 2), 0.5);
+    }
+
+    #[test]
+    fn opacity_of_nothing() {
+        let alpha = Alpha::from_pixel(4, 4, Luma([255]));
+        assert_eq!(get_opacity(&alpha, 1, 1, 0, 0), 0.0);
+    }
+}
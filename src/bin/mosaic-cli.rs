@@ -1,170 +1,443 @@
 use std::env;
+use std::fmt;
 use std::fs;
-use std::iter::Enumerate;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
 
 use image::{ImageBuffer, Rgb};
-use image::imageops::{crop, replace, resize};
-use itertools::Itertools;
-use rand::distributions::{Distribution, Uniform};
-use rayon::prelude::*;
-
 use mlib::*;
 
-pub fn get_pics_data(pics_dir: &Path) -> Vec<PicData> {
-    let paths = fs::read_dir(pics_dir).unwrap().map(|x| x.unwrap().path());
+/// Draws a progress bar on stderr, redrawn in place as events arrive.
+#[derive(Default)]
+pub struct TerminalProgress {
+    // Events from different threads can arrive out of order, so older counts
+    // than the last one drawn are dropped. Reset when a stage completes.
+    last_done: Mutex<usize>,
+}
 
-    let pics_data: Vec<Option<PicData>> = paths.par_bridge().map(get_pic_data).collect();
-    let mut pics_vec: Vec<PicData> = Vec::new();
-    for opt in pics_data {
-        match opt {
-            Some(data) => pics_vec.push(data),
-            None => {},
-        }
-    }
+impl TerminalProgress {
+    fn draw(&self, label: &str, progress: Progress) {
+        const WIDTH: usize = 30;
 
-    pics_vec
-}
+        let mut last_done = self.last_done.lock().unwrap();
+        if progress.done < *last_done {
+            return;
+        }
+        *last_done = if progress.done == progress.total {
+            0
+        } else {
+            progress.done
+        };
 
-fn get_match_data(
-    google_img: &ImageBuffer<Rgb<u8>, Vec<u8>>,
-    pics_data: &Vec<PicData>,
-    x_rulers: &Vec<u32>,
-    y_rulers: &Vec<u32>,
-    x_steps: Enumerate<std::slice::Iter<'_, u32>>,
-    y_steps: Enumerate<std::slice::Iter<'_, u32>>,
-) -> Vec<MatchData> {
-    x_steps
-        .cartesian_product(y_steps)
-        .par_bridge()
-        .map(|((i, &x), (j, &y))| {
-            let width = x_rulers[i + 1] - x;
-            let height = y_rulers[j + 1] - y;
-            println!("{}, {}, {}, {}, {}, {}", i, j, x, y, width, height);
-
-            let mut crop_img = google_img.clone();
-            let crop = crop(&mut crop_img, x, y, width, height).to_image();
-            let aspect = width as f64 / height as f64;
-            let thumbnail = resize(&crop, 128, 128, image::FilterType::Lanczos3);
-
-            let best_match = find_best_match(aspect, &thumbnail, &pics_data);
-            let best_image = image::open(best_match).unwrap().to_rgb();
-            let best_resize = resize(&best_image, width, height, image::FilterType::Lanczos3);
-
-            MatchData {
-                x: x,
-                y: y,
-                tile: best_resize,
+        let filled = (progress.get_fraction() * WIDTH as f64) as usize;
+        let eta = match progress.eta {
+            Some(eta) if progress.done < progress.total => {
+                format!(", {} left", format_duration(eta))
             }
-        })
-        .collect()
+            _ => String::new(),
+        };
+        eprint!(
+            "\r{} [{}{}] {}/{}{}\x1b[K",
+            label,
+            "#".repeat(filled),
+            " ".repeat(WIDTH - filled),
+            progress.done,
+            progress.total,
+            eta
+        );
+        if progress.done == progress.total {
+            eprintln!();
+        }
+    }
 }
 
-fn main() {
-    let mut base_dir = env::current_dir().unwrap();
-    base_dir.push("resources");
+impl ProgressSink for TerminalProgress {
+    fn library_scanned(&self, loaded: usize, progress: Progress) {
+        self.draw(&format!("{} pictures loaded", loaded), progress);
+    }
 
-    let mut reddit_pics_dir = base_dir.clone();
-    reddit_pics_dir.push("top_reddit_pics/");
+    fn layout_done(&self, cells: usize) {
+        eprintln!("Matching {} tiles", cells);
+    }
 
-    let mut google_pic_dir = base_dir.clone();
-    google_pic_dir.push("top_google_pic/");
+    fn cell_matched(&self, _match_data: &MatchData, progress: Progress) {
+        self.draw("Tiles placed", progress);
+    }
 
-    let mut mosaic_dir = base_dir.clone();
-    mosaic_dir.push("mosaic/");
+    fn render_written(&self, path: &Path) {
+        eprintln!("Saved {}", path.display());
+    }
+}
 
-    let google_img_name = fs::read_dir(google_pic_dir)
-        .unwrap()
-        .next()
-        .unwrap()
-        .unwrap();
-    let mut google_img = image::open(google_img_name.path()).unwrap().to_rgb();
+fn get_arg_value(name: &str) -> Option<String> {
+    let mut args = env::args();
+    args.find(|arg| arg == name)?;
+    args.next()
+}
 
-    let mut x_rulers = Vec::new();
+/// Parses the value of an option, exiting with a message if it is malformed.
+fn parse_arg<T: std::str::FromStr>(name: &str) -> Option<T> {
+    get_arg_value(name).map(|value| match value.parse() {
+        Ok(value) => value,
+        Err(_) => {
+            eprintln!("{} can't be {}", name, value);
+            process::exit(2);
+        }
+    })
+}
 
-    let mut pixels = 0;
-    let distribution = Uniform::new(120, 320);
-    let mut rng = rand::thread_rng();
-    loop {
-        let step = distribution.sample(&mut rng);
+/// Every value given for an option that may be repeated.
+fn get_arg_values(name: &str) -> Vec<String> {
+    let args: Vec<String> = env::args().collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| pair[1].clone())
+        .collect()
+}
 
-        if pixels + step > google_img.width() {
-            break;
+/// Resolves a photo named on the command line, exiting if it isn't in the
+/// library.
+fn get_library_path(library: &Library, name: &str) -> PathBuf {
+    match library.find_path(Path::new(name)) {
+        Some(path) => path,
+        None => {
+            eprintln!("{} is not in the library", name);
+            process::exit(2);
         }
-
-        pixels += step;
-        x_rulers.push(pixels);
     }
+}
 
-    let mut x_remaining = google_img.width() - pixels;
-    while x_remaining > 0 {
-        for i in 0..x_rulers.len() {
-            for j in i..x_rulers.len() {
-                x_rulers[j] += 1;
-            }
+/// Renders the mosaic saved in the project at `path` again, exiting if it,
+/// its target or its photos can't be read or the mosaic can't be saved.
+fn render_project(path: &Path) {
+    let project = match load_project(path) {
+        Ok(project) => project,
+        Err(err) => {
+            eprintln!("Couldn't open {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    };
+    let target = match open_target(&project.target) {
+        Ok(target) => target.get_scaled(project.config.output_scale),
+        Err(err) => {
+            eprintln!("Couldn't open {}: {}", project.target.display(), err);
+            process::exit(1);
+        }
+    };
+    let match_data = match get_project_match_data(&target.image, &project) {
+        Ok(match_data) => match_data,
+        Err(err) => {
+            eprintln!("Couldn't open the photos of {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    };
+    let output = render(&target.image, &match_data, project.config.grout.as_ref());
 
-            x_remaining -= 1;
-            if x_remaining <= 0 {
-                break;
-            }
+    let output_path = match &project.output {
+        Some(output_path) => output_path.clone(),
+        None => {
+            let mut output_path = env::current_dir().unwrap();
+            output_path.push("resources/mosaic/");
+            output_path.push(project.target.file_name().unwrap());
+            output_path
         }
+    };
+    let output_path = get_output_path(&output_path, &project.config);
+    let output = finish_mosaic(&output, &match_data, target.alpha.as_ref(), &project.config);
+    if let Err(err) = save_mosaic(&output, &output_path, &TerminalProgress::default()) {
+        eprintln!("Couldn't save {}: {}", output_path.display(), err);
+        process::exit(1);
+    }
+}
+
+/// Reports a file saved by one of the reports, or exits if it couldn't be.
+fn check_saved<E: fmt::Display>(result: Result<(), E>, path: &Path) {
+    if let Err(err) = result {
+        eprintln!("Couldn't save {}: {}", path.display(), err);
+        process::exit(1);
     }
+    println!("Saved {}", path.display());
+}
 
-    x_rulers.insert(0, 0);
+/// Prints how well the library covers the target's cells and saves the
+/// coverage plot and the poorly covered cells next to where the mosaic would
+/// go, instead of making the mosaic.
+fn report_coverage(
+    report: &CoverageReport,
+    input: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    mosaic_path: &Path,
+) {
+    const WORST_CELLS: usize = 10;
 
-    let mut y_rulers = Vec::new();
+    println!(
+        "{} of {} cells have no photo within \u{394}E {}",
+        report.get_poor_count(),
+        report.cells.len(),
+        POOR_COVERAGE_DELTA_E
+    );
+    println!("Worst matched cells:");
+    for cell in report.get_worst_cells(WORST_CELLS) {
+        println!(
+            "  {}x{} at {}, {}: score {:.3}, \u{394}E {:.1}",
+            cell.width, cell.height, cell.x, cell.y, cell.score, cell.delta_e
+        );
+    }
+
+    let plot_path = mosaic_path.with_extension("coverage.png");
+    check_saved(render_coverage_plot(report).save(&plot_path), &plot_path);
+    let gaps_path = mosaic_path.with_extension("gaps.png");
+    check_saved(
+        render_coverage_overlay(input, report).save(&gaps_path),
+        &gaps_path,
+    );
+}
 
-    let mut pixels = 0;
-    loop {
-        let step = distribution.sample(&mut rng);
+/// Prints the colours the target's under-served cells want and saves them
+/// as swatches and as text next to where the mosaic would go.
+fn report_gaps(report: &CoverageReport, mosaic_path: &Path) {
+    const SWATCH_SIZE: u32 = 48;
 
-        if pixels + step > google_img.height() {
-            break;
+    let threshold =
+        parse_arg("--gap-score").unwrap_or_else(|| report.get_score_quantile(DEFAULT_GAP_QUANTILE));
+    let gaps = get_gap_report(report, threshold);
+    let text = format_gap_report(&gaps);
+    print!("{}", text);
+
+    let text_path = mosaic_path.with_extension("wanted.txt");
+    check_saved(fs::write(&text_path, text), &text_path);
+    let swatches_path = mosaic_path.with_extension("wanted.png");
+    check_saved(
+        render_swatches(&gaps.swatches, SWATCH_SIZE).save(&swatches_path),
+        &swatches_path,
+    );
+}
+
+/// Parses a `--fill` of one colour, or of two separated by `:` for a
+/// gradient, exiting with a message if it is malformed.
+fn parse_fill(fill: &str) -> TextFill {
+    let colours: Option<Vec<_>> = fill.split(':').map(parse_colour).collect();
+    match colours.as_deref() {
+        Some([colour]) => TextFill::Flat(*colour),
+        Some([top, bottom]) => TextFill::Gradient(*top, *bottom),
+        _ => {
+            eprintln!("--fill must be a colour like #ff8000 or a gradient like #ff8000:#0080ff");
+            process::exit(2);
         }
+    }
+}
+
+/// Draws the `--text` target in the `--font` and saves it in `dir` under a
+/// new name, see `get_text_target_path`, so that it is opened and saved in
+/// projects like any other target without overwriting earlier ones.
+fn save_text_target(text: &str, dir: &Path) -> PathBuf {
+    let font_path = match get_arg_value("--font") {
+        Some(font_path) => PathBuf::from(font_path),
+        None => {
+            eprintln!("--text needs a --font");
+            process::exit(2);
+        }
+    };
+    let fill = match (get_arg_value("--fill-image"), get_arg_value("--fill")) {
+        (Some(path), _) => match open_image(Path::new(&path)) {
+            Ok(img) => TextFill::Image(img.to_rgb()),
+            Err(err) => {
+                eprintln!("Couldn't open {}: {}", path, err);
+                process::exit(1);
+            }
+        },
+        (None, Some(fill)) => parse_fill(&fill),
+        (None, None) => TextFill::Flat(Rgb([0, 0, 0])),
+    };
+
+    // Text is unescaped here so that `\n` on the command line starts a new line.
+    let text = text.replace("\\n", "\n");
+    let size = parse_arg("--text-size").unwrap_or(400.0);
+    let path = get_text_target_path(dir);
+    if let Err(err) = write_text_target(&text, &font_path, size, &fill, &path) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+    path
+}
 
-        pixels += step;
-        y_rulers.push(pixels);
+fn main() {
+    if let Some(path) = get_arg_value("--project") {
+        render_project(Path::new(&path));
+        return;
     }
 
-    let mut y_remaining = google_img.height() - pixels;
-    while y_remaining > 0 {
-        for i in 0..y_rulers.len() {
-            for j in i..y_rulers.len() {
-                y_rulers[j] += 1;
+    let defaults = MosaicConfig::default();
+    let layout = get_arg_value("--layout").map(|name| match Layout::from_name(&name) {
+        Some(layout) => layout,
+        None => {
+            eprintln!("--layout must be random or grid");
+            process::exit(2);
+        }
+    });
+    let background = get_arg_value("--background").map(|colour| match parse_colour(&colour) {
+        Some(colour) => colour,
+        None => {
+            eprintln!("--background must be a colour like #ffffff");
+            process::exit(2);
+        }
+    });
+    // Any of the grout options sets tiles in grout, the others as default.
+    let grout_options = ["--grout", "--grout-colour", "--corner-radius", "--shadow"];
+    let grout = if env::args().any(|arg| grout_options.contains(&arg.as_str())) {
+        let defaults = GroutConfig::default();
+        let colour = get_arg_value("--grout-colour").map(|colour| match parse_colour(&colour) {
+            Some(colour) => colour,
+            None => {
+                eprintln!("--grout-colour must be a colour like #404040");
+                process::exit(2);
             }
+        });
+        Some(GroutConfig {
+            width: parse_arg("--grout").unwrap_or(defaults.width),
+            colour: colour.unwrap_or(defaults.colour),
+            corner_radius: parse_arg("--corner-radius").unwrap_or(defaults.corner_radius),
+            shadow: parse_arg("--shadow").unwrap_or(defaults.shadow),
+        })
+    } else {
+        None
+    };
+    let config = MosaicConfig {
+        transforms: env::args().any(|arg| arg == "--transforms"),
+        cascade: if env::args().any(|arg| arg == "--cascade") {
+            Some(CascadeConfig::default())
+        } else {
+            None
+        },
+        min_tile: parse_arg("--min-tile").unwrap_or(defaults.min_tile),
+        max_tile: parse_arg("--max-tile").unwrap_or(defaults.max_tile),
+        layout: layout.unwrap_or(defaults.layout),
+        reuse_limit: parse_arg("--reuse-limit"),
+        colour_correction: parse_arg("--colour-correction").unwrap_or(defaults.colour_correction),
+        output_scale: parse_arg("--scale").unwrap_or(defaults.output_scale),
+        seed: parse_arg("--seed"),
+        mask_threshold: parse_arg("--mask-threshold").unwrap_or(defaults.mask_threshold),
+        background: background.unwrap_or(defaults.background),
+        rgba_output: env::args().any(|arg| arg == "--rgba"),
+        grout,
+        ..defaults
+    };
+    let thumb_size = parse_arg("--thumb-size").unwrap_or(DEFAULT_THUMB_SIZE);
+    if thumb_size == 0 {
+        eprintln!("--thumb-size must be at least 1");
+        process::exit(2);
+    }
+
+    let mut base_dir = env::current_dir().unwrap();
+    base_dir.push("resources");
+
+    let mut reddit_pics_dir = base_dir.clone();
+    reddit_pics_dir.push("top_reddit_pics/");
+
+    let mut google_pic_dir = base_dir.clone();
+    google_pic_dir.push("top_google_pic/");
 
-            y_remaining -= 1;
-            if y_remaining <= 0 {
-                break;
+    let mut mosaic_dir = base_dir.clone();
+    mosaic_dir.push("mosaic/");
+
+    let (target_path, output_name) = match get_arg_value("--text") {
+        Some(text) => (save_text_target(&text, &mosaic_dir), "text.png".into()),
+        None => {
+            let google_img_name = fs::read_dir(google_pic_dir)
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap();
+            (google_img_name.path(), google_img_name.file_name())
+        }
+    };
+    let mut target = open_target(&target_path).unwrap();
+    let mask_path = get_arg_value("--mask").map(PathBuf::from);
+    if let Some(mask_path) = &mask_path {
+        match open_mask(mask_path) {
+            Ok(mask) => target.set_mask(&mask),
+            Err(err) => {
+                eprintln!("Couldn't open {}: {}", mask_path.display(), err);
+                process::exit(1);
             }
         }
     }
+    let target = target.get_scaled(config.output_scale);
+    let google_img = &target.image;
 
-    y_rulers.insert(0, 0);
-
-    let pics_data = get_pics_data(&reddit_pics_dir);
-    let x_steps = x_rulers[0..x_rulers.len() - 1].iter().enumerate();
-    let y_steps = y_rulers[0..y_rulers.len() - 1].iter().enumerate();
-    let match_data = get_match_data(
-        &google_img,
-        &pics_data,
+    let (x_rulers, y_rulers) = get_layout(google_img.width(), google_img.height(), &config);
+    let cells = get_visible_cells(
         &x_rulers,
         &y_rulers,
-        x_steps,
-        y_steps,
+        target.get_mask(),
+        config.mask_threshold,
     );
 
-    for m in match_data.iter() {
-        replace(&mut google_img, &m.tile, m.x, m.y);
+    let progress = TerminalProgress::default();
+    let mut library = get_library(&reddit_pics_dir, thumb_size, &progress).unwrap();
+    if env::args().any(|arg| arg == "--dedupe") {
+        let max_distance = parse_arg("--duplicate-distance").unwrap_or(DEFAULT_DUPLICATE_DISTANCE);
+        print!("{}", format_duplicate_report(&library.dedupe(max_distance)));
+    }
+    for name in get_arg_values("--exclude") {
+        let path = get_library_path(&library, &name);
+        library.exclude(path);
+    }
+    for name in get_arg_values("--pin") {
+        let path = get_library_path(&library, &name);
+        library.pin(path);
+    }
+    mosaic_dir.push(output_name);
+    let mosaic_dir = get_output_path(&mosaic_dir, &config);
+
+    // Ctrl-C stops matching between cells rather than killing the process,
+    // so nothing is ever left half written. It is only caught once the
+    // library is loaded, as the scan writes nothing and can't be stopped.
+    let cancel = CancelToken::new();
+    let handler_cancel = cancel.clone();
+    ctrlc::set_handler(move || handler_cancel.cancel()).expect("Couldn't set Ctrl-C handler");
+
+    let coverage = env::args().any(|arg| arg == "--coverage");
+    let gaps = env::args().any(|arg| arg == "--gaps");
+    if coverage || gaps {
+        match get_coverage(google_img, &library, &cells, &config, &cancel) {
+            Some(report) => {
+                if coverage {
+                    report_coverage(&report, google_img, &mosaic_dir);
+                }
+                if gaps {
+                    report_gaps(&report, &mosaic_dir);
+                }
+            }
+            None => {
+                eprintln!("Cancelled");
+                process::exit(130);
+            }
+        }
+        return;
     }
 
-    mosaic_dir.push(google_img_name.file_name());
-    image::save_buffer(
-        &mosaic_dir,
-        &google_img,
-        google_img.width(),
-        google_img.height(),
-        image::ColorType::RGB(8),
-    )
-    .unwrap();
+    let match_data = get_match_data(google_img, &library, &cells, &config, &cancel, &progress);
+    let match_data = match match_data {
+        Ok(match_data) => match_data,
+        Err(MatchError::Cancelled) => {
+            eprintln!("Cancelled, no mosaic written");
+            process::exit(130);
+        }
+        Err(err) => {
+            eprintln!("{}, no mosaic written", err);
+            process::exit(1);
+        }
+    };
+
+    let output = render(google_img, &match_data, config.grout.as_ref());
+    let output = finish_mosaic(&output, &match_data, target.alpha.as_ref(), &config);
+    save_mosaic(&output, &mosaic_dir, &progress).unwrap();
+
+    if let Some(path) = get_arg_value("--save-project") {
+        let mut project = Project::new(&library, &target_path, &config, &match_data);
+        project.mask = mask_path;
+        project.output = Some(mosaic_dir);
+        save_project(&project, Path::new(&path)).unwrap();
+    }
 }
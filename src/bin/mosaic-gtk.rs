@@ -1,20 +1,22 @@
 use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::env::args;
 use std::fs;
-use std::iter::Enumerate;
-use std::path::Path;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+use gdk::prelude::*;
+use gdk_pixbuf::{Colorspace, Pixbuf};
 use gio::prelude::*;
 use glib::clone;
 use gtk::prelude::*;
-use image::imageops::{crop, replace, resize};
-use image::{ImageBuffer, Rgb};
-use itertools::Itertools;
-use rand::distributions::{Distribution, Uniform};
-use rayon::prelude::*;
+use image::imageops::resize;
+use image::{DynamicImage, ImageBuffer, Rgb};
 
 use mlib::*;
 
@@ -37,6 +39,10 @@ pub fn main() {
                 .take()
                 .expect("Shutdown called multiple times");
             // Here we could do whatever we need to do for shutdown now
+            let config = application.widgets.main_view.settings.get_config();
+            if let Err(err) = save_settings(&config) {
+                eprintln!("Couldn't save settings: {}", err);
+            }
             drop(application);
         });
     });
@@ -45,17 +51,66 @@ pub fn main() {
     application.run(&args().collect::<Vec<_>>());
 }
 
+/// Progress events forwarded from worker threads to the main loop, where the
+/// widgets can be updated.
+pub enum ProgressMessage {
+    LibraryScanned(usize, Progress),
+    LayoutDone(usize),
+    CellMatched(MatchData, Progress),
+    Rendered(DynamicImage, Vec<MatchData>),
+    ProjectOpened(Mosaic),
+    /// The coverage of the target's cells, with the scaled target analysed.
+    CoverageAnalysed(CoverageReport, ImageBuffer<Rgb<u8>, Vec<u8>>),
+    RenderWritten(PathBuf),
+    /// Something went wrong, the message is shown to the user.
+    Failed(String),
+    Finished,
+}
+
+pub struct ChannelProgress {
+    sender: Mutex<glib::Sender<ProgressMessage>>,
+}
+
+impl ChannelProgress {
+    pub fn new(sender: glib::Sender<ProgressMessage>) -> Self {
+        ChannelProgress {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    pub fn send(&self, message: ProgressMessage) {
+        // The receiving window may already have been closed.
+        let _ = self.sender.lock().unwrap().send(message);
+    }
+}
+
+impl ProgressSink for ChannelProgress {
+    fn library_scanned(&self, loaded: usize, progress: Progress) {
+        self.send(ProgressMessage::LibraryScanned(loaded, progress));
+    }
+
+    fn layout_done(&self, cells: usize) {
+        self.send(ProgressMessage::LayoutDone(cells));
+    }
+
+    fn cell_matched(&self, match_data: &MatchData, progress: Progress) {
+        self.send(ProgressMessage::CellMatched(match_data.clone(), progress));
+    }
+
+    fn render_written(&self, path: &Path) {
+        self.send(ProgressMessage::RenderWritten(path.to_path_buf()));
+    }
+}
+
 pub struct Application {
     pub widgets: Rc<Widgets>,
 }
 
 impl Application {
     pub fn new(app: &gtk::Application) -> Self {
-        let app = Application {
+        Application {
             widgets: Rc::new(Widgets::new(app)),
-        };
-
-        app
+        }
     }
 }
 
@@ -69,13 +124,13 @@ impl Widgets {
     pub fn new(application: &gtk::Application) -> Self {
         let header = Header::new();
         let window = gtk::ApplicationWindow::new(application);
-        let main_view = MainView::new(&window);
+        let main_view = MainView::new(&window, &header);
         window.set_icon_name(Some("package-x-generic"));
         window.set_property_window_position(gtk::WindowPosition::Center);
         window.set_titlebar(Some(&header.container));
         window.add(&main_view.container);
         window.show_all();
-        window.set_default_size(500, 250);
+        window.set_default_size(1000, 600);
         window.connect_delete_event(move |window, _| {
             window.close();
             Inhibit(false)
@@ -91,6 +146,15 @@ impl Widgets {
 
 pub struct Header {
     container: gtk::HeaderBar,
+    pub open_button: gtk::Button,
+    pub save_button: gtk::Button,
+    pub save_as_button: gtk::Button,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header::new()
+    }
 }
 
 impl Header {
@@ -99,78 +163,1573 @@ impl Header {
         container.set_title(Some("Photo Mosaic"));
         container.set_show_close_button(true);
 
-        Header { container }
+        let open_button = gtk::Button::with_label("Open");
+        let save_button = gtk::Button::with_label("Save");
+        let save_as_button = gtk::Button::with_label("Save As");
+        container.pack_start(&open_button);
+        container.pack_end(&save_as_button);
+        container.pack_end(&save_button);
+
+        Header {
+            container,
+            open_button,
+            save_button,
+            save_as_button,
+        }
+    }
+}
+
+/// Where the preview is looking, in screen pixels.
+#[derive(Default)]
+pub struct PreviewView {
+    /// Screen pixels per image pixel, or `None` to fit the whole image.
+    pub zoom: Option<f64>,
+    /// Where the image's top left corner is drawn when zoomed.
+    pub offset: (f64, f64),
+    /// Pointer position while the image is being dragged.
+    pub drag: Option<(f64, f64)>,
+    /// Where the current drag started, to tell clicks from drags.
+    pub press: Option<(f64, f64)>,
+}
+
+type ClickHandler = Box<dyn Fn(u32, u32)>;
+
+/// Shows the target with tiles appearing as they are matched, then the
+/// finished mosaic. Scroll to zoom, drag to pan and double click to fit.
+#[derive(Clone)]
+pub struct Preview {
+    pub container: gtk::DrawingArea,
+    pub pixbuf: Rc<RefCell<Option<Pixbuf>>>,
+    pub view: Rc<RefCell<PreviewView>>,
+    /// Called with the image pixel under the pointer when it is clicked.
+    pub click_handler: Rc<RefCell<Option<ClickHandler>>>,
+}
+
+impl Default for Preview {
+    fn default() -> Self {
+        Preview::new()
+    }
+}
+
+impl Preview {
+    pub fn new() -> Self {
+        let container = gtk::DrawingArea::new();
+        container.set_size_request(400, 300);
+        container.set_hexpand(true);
+        container.set_vexpand(true);
+        container.add_events(
+            gdk::EventMask::SCROLL_MASK
+                | gdk::EventMask::BUTTON_PRESS_MASK
+                | gdk::EventMask::BUTTON_RELEASE_MASK
+                | gdk::EventMask::BUTTON1_MOTION_MASK,
+        );
+
+        let preview = Preview {
+            container,
+            pixbuf: Rc::new(RefCell::new(None)),
+            view: Rc::new(RefCell::new(PreviewView::default())),
+            click_handler: Rc::new(RefCell::new(None)),
+        };
+
+        preview
+            .container
+            .connect_draw(clone!(@strong preview => move |area, cr| {
+                cr.set_source_rgb(0.2, 0.2, 0.2);
+                cr.paint();
+
+                if let Some(pixbuf) = preview.pixbuf.borrow().as_ref() {
+                    let (scale, x, y) = preview.get_transform(area, pixbuf);
+                    cr.translate(x, y);
+                    cr.scale(scale, scale);
+                    cr.set_source_pixbuf(pixbuf, 0.0, 0.0);
+                    cr.paint();
+                }
+
+                Inhibit(false)
+            }));
+
+        preview
+            .container
+            .connect_scroll_event(clone!(@strong preview => move |area, event| {
+                let factor = match event.get_direction() {
+                    gdk::ScrollDirection::Up => 1.25,
+                    gdk::ScrollDirection::Down => 0.8,
+                    gdk::ScrollDirection::Smooth if event.get_delta().1 < 0.0 => 1.25,
+                    gdk::ScrollDirection::Smooth if event.get_delta().1 > 0.0 => 0.8,
+                    _ => return Inhibit(false),
+                };
+                if let Some(pixbuf) = preview.pixbuf.borrow().as_ref() {
+                    // Zoom around the pointer, keeping the pixel under it still.
+                    let (scale, x, y) = preview.get_transform(area, pixbuf);
+                    let (pointer_x, pointer_y) = event.get_position();
+                    let zoom = (scale * factor).clamp(0.01, 32.0);
+                    let mut view = preview.view.borrow_mut();
+                    view.offset = (
+                        pointer_x - (pointer_x - x) / scale * zoom,
+                        pointer_y - (pointer_y - y) / scale * zoom,
+                    );
+                    view.zoom = Some(zoom);
+                    area.queue_draw();
+                }
+
+                Inhibit(true)
+            }));
+
+        preview.container.connect_button_press_event(
+            clone!(@strong preview => move |area, event| {
+                if event.get_button() != 1 {
+                    return Inhibit(false);
+                }
+                if event.get_event_type() == gdk::EventType::DoubleButtonPress {
+                    *preview.view.borrow_mut() = PreviewView::default();
+                    area.queue_draw();
+                } else if let Some(pixbuf) = preview.pixbuf.borrow().as_ref() {
+                    // Start dragging from wherever the image is now, even if fitted.
+                    let (scale, x, y) = preview.get_transform(area, pixbuf);
+                    let mut view = preview.view.borrow_mut();
+                    view.zoom = Some(scale);
+                    view.offset = (x, y);
+                    view.drag = Some(event.get_position());
+                    view.press = Some(event.get_position());
+                }
+
+                Inhibit(true)
+            }),
+        );
+
+        preview.container.connect_motion_notify_event(
+            clone!(@strong preview => move |area, event| {
+                let mut view = preview.view.borrow_mut();
+                if let Some((drag_x, drag_y)) = view.drag {
+                    let (pointer_x, pointer_y) = event.get_position();
+                    view.offset.0 += pointer_x - drag_x;
+                    view.offset.1 += pointer_y - drag_y;
+                    view.drag = Some((pointer_x, pointer_y));
+                    area.queue_draw();
+                }
+
+                Inhibit(true)
+            }),
+        );
+
+        preview.container.connect_button_release_event(
+            clone!(@strong preview => move |area, event| {
+                let press = {
+                    let mut view = preview.view.borrow_mut();
+                    view.drag = None;
+                    view.press.take()
+                };
+
+                let (pointer_x, pointer_y) = event.get_position();
+                let clicked = match press {
+                    Some((press_x, press_y)) => {
+                        (pointer_x - press_x).abs() < 4.0 && (pointer_y - press_y).abs() < 4.0
+                    }
+                    None => false,
+                };
+                let pixel = match preview.pixbuf.borrow().as_ref() {
+                    Some(pixbuf) if clicked => {
+                        let (scale, x, y) = preview.get_transform(area, pixbuf);
+                        let pixel_x = (pointer_x - x) / scale;
+                        let pixel_y = (pointer_y - y) / scale;
+                        let inside = pixel_x >= 0.0
+                            && pixel_y >= 0.0
+                            && pixel_x < pixbuf.get_width() as f64
+                            && pixel_y < pixbuf.get_height() as f64;
+                        if inside {
+                            Some((pixel_x as u32, pixel_y as u32))
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                };
+
+                // No borrows may be held here, the handler is free to redraw.
+                if let Some((pixel_x, pixel_y)) = pixel {
+                    if let Some(handler) = preview.click_handler.borrow().as_ref() {
+                        handler(pixel_x, pixel_y);
+                    }
+                }
+
+                Inhibit(true)
+            }),
+        );
+
+        preview
+    }
+
+    pub fn connect_clicked<F: Fn(u32, u32) + 'static>(&self, handler: F) {
+        *self.click_handler.borrow_mut() = Some(Box::new(handler));
+    }
+
+    /// Returns the scale and position the image is drawn at.
+    fn get_transform(&self, area: &gtk::DrawingArea, pixbuf: &Pixbuf) -> (f64, f64, f64) {
+        let view = self.view.borrow();
+        match view.zoom {
+            Some(zoom) => (zoom, view.offset.0, view.offset.1),
+            None => {
+                let width = area.get_allocated_width() as f64;
+                let height = area.get_allocated_height() as f64;
+                let image_width = pixbuf.get_width() as f64;
+                let image_height = pixbuf.get_height() as f64;
+                let scale = (width / image_width).min(height / image_height);
+                let x = (width - image_width * scale) / 2.0;
+                let y = (height - image_height * scale) / 2.0;
+                (scale, x, y)
+            }
+        }
+    }
+
+    /// Shows a new image, fitted to the widget.
+    pub fn set_image(&self, img: &ImageBuffer<Rgb<u8>, Vec<u8>>) {
+        *self.pixbuf.borrow_mut() = Some(get_pixbuf(img));
+        *self.view.borrow_mut() = PreviewView::default();
+        self.container.queue_draw();
+    }
+
+    /// Shows a changed version of the current image, keeping the zoom and
+    /// position.
+    pub fn update_image(&self, img: &ImageBuffer<Rgb<u8>, Vec<u8>>) {
+        *self.pixbuf.borrow_mut() = Some(get_pixbuf(img));
+        self.container.queue_draw();
+    }
+
+    /// Draws a tile over the current image at `x`, `y`.
+    pub fn place_tile(&self, x: u32, y: u32, tile: &ImageBuffer<Rgb<u8>, Vec<u8>>) {
+        if let Some(pixbuf) = self.pixbuf.borrow().as_ref() {
+            let tile_pixbuf = get_pixbuf(tile);
+            let width = tile_pixbuf.get_width();
+            let height = tile_pixbuf.get_height();
+            tile_pixbuf.copy_area(0, 0, width, height, pixbuf, x as i32, y as i32);
+            self.container.queue_draw();
+        }
+    }
+}
+
+fn get_pixbuf(img: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Pixbuf {
+    let (width, height) = img.dimensions();
+    Pixbuf::from_mut_slice(
+        img.clone().into_raw(),
+        Colorspace::Rgb,
+        false,
+        8,
+        width as i32,
+        height as i32,
+        width as i32 * 3,
+    )
+}
+
+/// The mosaic parameters that can be changed before creating one, starting
+/// from those used last time.
+#[derive(Clone)]
+pub struct SettingsPanel {
+    pub container: gtk::Grid,
+    pub min_tile_spin: gtk::SpinButton,
+    pub max_tile_spin: gtk::SpinButton,
+    pub layout_combo: gtk::ComboBoxText,
+    pub reuse_limit_spin: gtk::SpinButton,
+    pub colour_correction_scale: gtk::Scale,
+    pub output_scale_spin: gtk::SpinButton,
+    pub seed_entry: gtk::Entry,
+    pub transforms_check_button: gtk::CheckButton,
+    pub cascade_check_button: gtk::CheckButton,
+    pub rgba_check_button: gtk::CheckButton,
+    pub mask_chooser_button: gtk::FileChooserButton,
+    pub clear_mask_button: gtk::Button,
+    pub mask_threshold_spin: gtk::SpinButton,
+    pub background_button: gtk::ColorButton,
+    pub grout_check_button: gtk::CheckButton,
+    pub grout_width_spin: gtk::SpinButton,
+    pub grout_colour_button: gtk::ColorButton,
+    pub corner_radius_spin: gtk::SpinButton,
+    pub shadow_spin: gtk::SpinButton,
+}
+
+impl Default for SettingsPanel {
+    fn default() -> Self {
+        SettingsPanel::new()
+    }
+}
+
+impl SettingsPanel {
+    pub fn new() -> Self {
+        let min_tile_spin = gtk::SpinButton::with_range(8.0, 2048.0, 8.0);
+        let max_tile_spin = gtk::SpinButton::with_range(8.0, 2048.0, 8.0);
+        // Keep the range the right way round whichever end is moved.
+        min_tile_spin.connect_value_changed(clone!(@weak max_tile_spin => move |spin| {
+            if spin.get_value() > max_tile_spin.get_value() {
+                max_tile_spin.set_value(spin.get_value());
+            }
+        }));
+        max_tile_spin.connect_value_changed(clone!(@weak min_tile_spin => move |spin| {
+            if spin.get_value() < min_tile_spin.get_value() {
+                min_tile_spin.set_value(spin.get_value());
+            }
+        }));
+
+        let layout_combo = gtk::ComboBoxText::new();
+        layout_combo.append(Some(Layout::Random.name()), "Random Rows and Columns");
+        layout_combo.append(Some(Layout::Grid.name()), "Even Grid");
+
+        let reuse_limit_spin = gtk::SpinButton::with_range(0.0, 1000.0, 1.0);
+        reuse_limit_spin.set_tooltip_text(Some("0 lets a picture be used any number of times"));
+
+        let colour_correction_scale =
+            gtk::Scale::with_range(gtk::Orientation::Horizontal, 0.0, 1.0, 0.05);
+        colour_correction_scale.set_digits(2);
+        colour_correction_scale.set_hexpand(true);
+
+        let output_scale_spin = gtk::SpinButton::with_range(0.1, 8.0, 0.1);
+
+        let seed_entry = gtk::Entry::new();
+        seed_entry.set_placeholder_text(Some("Random"));
+
+        let transforms_check_button = gtk::CheckButton::with_label("Mirror and Rotate Pictures");
+        let cascade_check_button = gtk::CheckButton::with_label("Fast Matching");
+        let rgba_check_button = gtk::CheckButton::with_label("Transparent Output");
+        rgba_check_button.set_tooltip_text(Some(
+            "Keep the photo's transparent parts transparent in the mosaic",
+        ));
+
+        let mask_chooser_button =
+            gtk::FileChooserButton::new("Select Mask", gtk::FileChooserAction::Open);
+        mask_chooser_button.set_hexpand(true);
+        mask_chooser_button
+            .set_tooltip_text(Some("Only place tiles where the mask is light or opaque"));
+        let clear_mask_button = gtk::Button::with_label("Clear");
+        clear_mask_button.connect_clicked(clone!(@weak mask_chooser_button => move |_| {
+            mask_chooser_button.unselect_all();
+        }));
+        let mask_box = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        mask_box.add(&mask_chooser_button);
+        mask_box.add(&clear_mask_button);
+
+        let mask_threshold_spin = gtk::SpinButton::with_range(0.0, 1.0, 0.05);
+        mask_threshold_spin.set_digits(2);
+        mask_threshold_spin.set_tooltip_text(Some(
+            "How much of a cell the mask must cover for it to get a tile",
+        ));
+
+        let background_button = gtk::ColorButton::new();
+        background_button.set_tooltip_text(Some("What fills the mosaic where there are no tiles"));
+
+        let grout_check_button = gtk::CheckButton::new();
+        grout_check_button.set_tooltip_text(Some("Leave lines between the tiles"));
+        let grout_width_spin = gtk::SpinButton::with_range(0.0, 256.0, 1.0);
+        grout_width_spin.set_tooltip_text(Some("Pixels between neighbouring tiles"));
+        let grout_colour_button = gtk::ColorButton::new();
+        let corner_radius_spin = gtk::SpinButton::with_range(0.0, 1024.0, 1.0);
+        let shadow_spin = gtk::SpinButton::with_range(0.0, 256.0, 1.0);
+        shadow_spin.set_tooltip_text(Some("How far each tile's shadow falls, 0 for none"));
+        let grout_box = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        grout_box.add(&grout_check_button);
+        grout_box.add(&grout_width_spin);
+        grout_box.add(&grout_colour_button);
+        // The grout's own settings only matter while there is grout.
+        grout_check_button.connect_toggled(clone!(@weak grout_width_spin, @weak grout_colour_button, @weak corner_radius_spin, @weak shadow_spin => move |button| {
+            let active = button.get_active();
+            grout_width_spin.set_sensitive(active);
+            grout_colour_button.set_sensitive(active);
+            corner_radius_spin.set_sensitive(active);
+            shadow_spin.set_sensitive(active);
+        }));
+
+        let container = gtk::Grid::new();
+        let rows: [(&str, &gtk::Widget); 13] = [
+            ("Smallest Tile", min_tile_spin.upcast_ref()),
+            ("Largest Tile", max_tile_spin.upcast_ref()),
+            ("Layout", layout_combo.upcast_ref()),
+            ("Uses per Picture", reuse_limit_spin.upcast_ref()),
+            ("Colour Correction", colour_correction_scale.upcast_ref()),
+            ("Output Scale", output_scale_spin.upcast_ref()),
+            ("Seed", seed_entry.upcast_ref()),
+            ("Mask", mask_box.upcast_ref()),
+            ("Mask Coverage", mask_threshold_spin.upcast_ref()),
+            ("Background", background_button.upcast_ref()),
+            ("Grout", grout_box.upcast_ref()),
+            ("Corner Radius", corner_radius_spin.upcast_ref()),
+            ("Shadow", shadow_spin.upcast_ref()),
+        ];
+        for (row, (label, widget)) in rows.iter().enumerate() {
+            let label = gtk::Label::new(Some(label));
+            label.set_halign(gtk::Align::Start);
+            container.attach(&label, 0, row as i32, 1, 1);
+            container.attach(*widget, 1, row as i32, 1, 1);
+        }
+        container.attach(&transforms_check_button, 0, 13, 2, 1);
+        container.attach(&cascade_check_button, 0, 14, 2, 1);
+        container.attach(&rgba_check_button, 0, 15, 2, 1);
+        container.set_row_spacing(6);
+        container.set_column_spacing(6);
+
+        let panel = SettingsPanel {
+            container,
+            min_tile_spin,
+            max_tile_spin,
+            layout_combo,
+            reuse_limit_spin,
+            colour_correction_scale,
+            output_scale_spin,
+            seed_entry,
+            transforms_check_button,
+            cascade_check_button,
+            rgba_check_button,
+            mask_chooser_button,
+            clear_mask_button,
+            mask_threshold_spin,
+            background_button,
+            grout_check_button,
+            grout_width_spin,
+            grout_colour_button,
+            corner_radius_spin,
+            shadow_spin,
+        };
+        panel.set_config(&load_settings());
+
+        panel
+    }
+
+    pub fn set_config(&self, config: &MosaicConfig) {
+        self.min_tile_spin.set_value(config.min_tile as f64);
+        self.max_tile_spin.set_value(config.max_tile as f64);
+        self.layout_combo.set_active_id(Some(config.layout.name()));
+        self.reuse_limit_spin
+            .set_value(config.reuse_limit.unwrap_or(0) as f64);
+        self.colour_correction_scale
+            .set_value(config.colour_correction);
+        self.output_scale_spin.set_value(config.output_scale);
+        match config.seed {
+            Some(seed) => self.seed_entry.set_text(&seed.to_string()),
+            None => self.seed_entry.set_text(""),
+        }
+        self.transforms_check_button.set_active(config.transforms);
+        self.cascade_check_button
+            .set_active(config.cascade.is_some());
+        self.rgba_check_button.set_active(config.rgba_output);
+        self.mask_threshold_spin.set_value(config.mask_threshold);
+        set_colour(&self.background_button, config.background);
+        // Without grout its settings are left at the defaults, ready for
+        // when it is turned on.
+        let grout = config.grout.clone().unwrap_or_default();
+        self.grout_width_spin.set_value(grout.width as f64);
+        set_colour(&self.grout_colour_button, grout.colour);
+        self.corner_radius_spin
+            .set_value(grout.corner_radius as f64);
+        self.shadow_spin.set_value(grout.shadow as f64);
+        self.grout_check_button.set_active(config.grout.is_some());
+        self.grout_check_button.toggled();
+    }
+
+    pub fn get_config(&self) -> MosaicConfig {
+        let layout = self
+            .layout_combo
+            .get_active_id()
+            .and_then(|id| Layout::from_name(&id))
+            .unwrap_or_default();
+        let reuse_limit = self.reuse_limit_spin.get_value_as_int() as usize;
+
+        MosaicConfig {
+            transforms: self.transforms_check_button.get_active(),
+            cascade: if self.cascade_check_button.get_active() {
+                Some(CascadeConfig::default())
+            } else {
+                None
+            },
+            min_tile: self.min_tile_spin.get_value_as_int() as u32,
+            max_tile: self.max_tile_spin.get_value_as_int() as u32,
+            layout,
+            reuse_limit: if reuse_limit == 0 {
+                None
+            } else {
+                Some(reuse_limit)
+            },
+            colour_correction: self.colour_correction_scale.get_value(),
+            output_scale: self.output_scale_spin.get_value(),
+            seed: self.seed_entry.get_text().trim().parse().ok(),
+            mask_threshold: self.mask_threshold_spin.get_value(),
+            background: get_colour(&self.background_button),
+            rgba_output: self.rgba_check_button.get_active(),
+            grout: if self.grout_check_button.get_active() {
+                Some(GroutConfig {
+                    width: self.grout_width_spin.get_value_as_int() as u32,
+                    colour: get_colour(&self.grout_colour_button),
+                    corner_radius: self.corner_radius_spin.get_value_as_int() as u32,
+                    shadow: self.shadow_spin.get_value_as_int() as u32,
+                })
+            } else {
+                None
+            },
+            ..MosaicConfig::default()
+        }
+    }
+
+    /// The mask chosen, if any. It isn't kept between sessions as it belongs
+    /// to the photo rather than to the settings.
+    pub fn get_mask_path(&self) -> Option<PathBuf> {
+        self.mask_chooser_button.get_filename()
+    }
+
+    pub fn set_mask_path(&self, path: Option<&Path>) {
+        match path {
+            Some(path) => {
+                self.mask_chooser_button.set_filename(path);
+            }
+            None => self.mask_chooser_button.unselect_all(),
+        }
+    }
+
+    /// Applies the chosen mask to `target` and scales it as `config` asks,
+    /// see `Target::get_scaled`.
+    pub fn get_target(&self, target: &Target, config: &MosaicConfig) -> Result<Target, String> {
+        let mut target = target.clone();
+        if let Some(path) = self.get_mask_path() {
+            let mask = open_mask(&path)
+                .map_err(|err| format!("Couldn't open {}: {}", path.display(), err))?;
+            target.set_mask(&mask);
+        }
+        Ok(target.get_scaled(config.output_scale))
+    }
+}
+
+/// Calls `handler` with the local files and folders dropped on `widget`,
+/// which is highlighted while something is dragged over it.
+fn connect_drop<W: IsA<gtk::Widget>, F: Fn(Vec<PathBuf>) + 'static>(widget: &W, handler: F) {
+    let targets = [gtk::TargetEntry::new(
+        "text/uri-list",
+        gtk::TargetFlags::OTHER_APP,
+        0,
+    )];
+    widget.drag_dest_set(gtk::DestDefaults::ALL, &targets, gdk::DragAction::COPY);
+    widget.connect_drag_data_received(move |_, _, _, _, data, _, _| {
+        let paths: Vec<PathBuf> = data
+            .get_uris()
+            .iter()
+            .filter_map(|uri| glib::filename_from_uri(uri).ok())
+            .map(|(path, _)| path)
+            .collect();
+        if !paths.is_empty() {
+            handler(paths);
+        }
+    });
+}
+
+/// Shows `message` in an error dialog over `window` and waits for it to be
+/// closed.
+fn show_error(window: &gtk::ApplicationWindow, message: &str) {
+    let dialog = gtk::MessageDialog::new(
+        Some(window),
+        gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+        gtk::MessageType::Error,
+        gtk::ButtonsType::Close,
+        message,
+    );
+    dialog.run();
+    dialog.close();
+}
+
+/// Shows `text` too long for a message dialog, such as a report, scrollable.
+fn show_report(window: &gtk::ApplicationWindow, title: &str, text: &str) {
+    let dialog = gtk::Dialog::with_buttons(
+        Some(title),
+        Some(window),
+        gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+        &[("Close", gtk::ResponseType::Close)],
+    );
+    dialog.set_default_size(600, 400);
+
+    let text_view = gtk::TextView::new();
+    text_view.set_editable(false);
+    text_view.set_property_monospace(true);
+    if let Some(buffer) = text_view.get_buffer() {
+        buffer.set_text(text);
+    }
+    let scrolled_window = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
+    scrolled_window.set_vexpand(true);
+    scrolled_window.add(&text_view);
+    dialog.get_content_area().add(&scrolled_window);
+    dialog.show_all();
+
+    dialog.run();
+    dialog.close();
+}
+
+fn get_colour(button: &gtk::ColorButton) -> Rgb<u8> {
+    let rgba = button.get_rgba();
+    Rgb([
+        (rgba.red * 255.0).round() as u8,
+        (rgba.green * 255.0).round() as u8,
+        (rgba.blue * 255.0).round() as u8,
+    ])
+}
+
+fn set_colour(button: &gtk::ColorButton, colour: Rgb<u8>) {
+    let [r, g, b] = colour.data;
+    button.set_rgba(&gdk::RGBA {
+        red: r as f64 / 255.0,
+        green: g as f64 / 255.0,
+        blue: b as f64 / 255.0,
+        alpha: 1.0,
+    });
+}
+
+/// Asks for some text, a font and what to fill the letters with, and saves
+/// the text drawn that way as a target in the cache, see `get_text_target`.
+/// Returns where it was saved, or `None` if the dialog was cancelled.
+fn show_text_dialog(window: &gtk::ApplicationWindow) -> Option<Result<PathBuf, String>> {
+    let dialog = gtk::Dialog::with_buttons(
+        Some("Use Text"),
+        Some(window),
+        gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+        &[
+            ("Use Text", gtk::ResponseType::Ok),
+            ("Cancel", gtk::ResponseType::Cancel),
+        ],
+    );
+
+    let text_view = gtk::TextView::new();
+    text_view.set_size_request(300, 80);
+    let text_frame = gtk::Frame::new(None);
+    text_frame.add(&text_view);
+
+    let font_chooser_button =
+        gtk::FileChooserButton::new("Select Font", gtk::FileChooserAction::Open);
+    let filter = gtk::FileFilter::new();
+    filter.set_name(Some("Fonts"));
+    filter.add_pattern("*.ttf");
+    filter.add_pattern("*.otf");
+    filter.add_pattern("*.TTF");
+    filter.add_pattern("*.OTF");
+    font_chooser_button.add_filter(&filter);
+    font_chooser_button.set_current_folder("/usr/share/fonts");
+
+    let size_spin = gtk::SpinButton::with_range(16.0, 4000.0, 16.0);
+    size_spin.set_value(400.0);
+    size_spin.set_tooltip_text(Some("Pixels from one line to the next"));
+
+    let fill_combo = gtk::ComboBoxText::new();
+    fill_combo.append(Some("flat"), "One Colour");
+    fill_combo.append(Some("gradient"), "Gradient");
+    fill_combo.append(Some("image"), "Photo");
+    fill_combo.set_active_id(Some("flat"));
+
+    let top_button = gtk::ColorButton::new();
+    set_colour(&top_button, Rgb([0, 0, 0]));
+    let bottom_button = gtk::ColorButton::new();
+    set_colour(&bottom_button, Rgb([0, 0, 0]));
+    let colours_box = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+    colours_box.add(&top_button);
+    colours_box.add(&bottom_button);
+    let image_chooser_button =
+        gtk::FileChooserButton::new("Select Photo", gtk::FileChooserAction::Open);
+
+    let update_fill = clone!(@weak bottom_button, @weak colours_box, @weak image_chooser_button => move |combo: &gtk::ComboBoxText| {
+        let fill = combo.get_active_id();
+        let fill = fill.as_ref().map(|id| id.as_str());
+        bottom_button.set_visible(fill == Some("gradient"));
+        colours_box.set_sensitive(fill != Some("image"));
+        image_chooser_button.set_sensitive(fill == Some("image"));
+    });
+    fill_combo.connect_changed(update_fill.clone());
+
+    let grid = gtk::Grid::new();
+    let rows: [(&str, &gtk::Widget); 6] = [
+        ("Text", text_frame.upcast_ref()),
+        ("Font", font_chooser_button.upcast_ref()),
+        ("Size", size_spin.upcast_ref()),
+        ("Fill", fill_combo.upcast_ref()),
+        ("Colours", colours_box.upcast_ref()),
+        ("Photo", image_chooser_button.upcast_ref()),
+    ];
+    for (row, (label, widget)) in rows.iter().enumerate() {
+        let label = gtk::Label::new(Some(label));
+        label.set_halign(gtk::Align::Start);
+        grid.attach(&label, 0, row as i32, 1, 1);
+        grid.attach(*widget, 1, row as i32, 1, 1);
+    }
+    grid.set_row_spacing(6);
+    grid.set_column_spacing(6);
+    grid.set_border_width(6);
+    dialog.get_content_area().add(&grid);
+    dialog.show_all();
+    update_fill(&fill_combo);
+
+    let result = loop {
+        if dialog.run() != gtk::ResponseType::Ok {
+            break None;
+        }
+
+        let text = text_view
+            .get_buffer()
+            .and_then(|buffer| {
+                buffer.get_text(&buffer.get_start_iter(), &buffer.get_end_iter(), false)
+            })
+            .map(|text| text.to_string())
+            .unwrap_or_default();
+        if text.trim().is_empty() {
+            show_error(window, "Type some text first");
+            continue;
+        }
+        let font_path = match font_chooser_button.get_filename() {
+            Some(path) => path,
+            None => {
+                show_error(window, "Select a font first");
+                continue;
+            }
+        };
+        let fill = match fill_combo.get_active_id().as_ref().map(|id| id.as_str()) {
+            Some("gradient") => {
+                TextFill::Gradient(get_colour(&top_button), get_colour(&bottom_button))
+            }
+            Some("image") => {
+                let path = match image_chooser_button.get_filename() {
+                    Some(path) => path,
+                    None => {
+                        show_error(window, "Select a photo to fill the text with first");
+                        continue;
+                    }
+                };
+                match open_image(&path) {
+                    Ok(img) => TextFill::Image(img.to_rgb()),
+                    Err(err) => {
+                        show_error(
+                            window,
+                            &format!("Couldn't open {}: {}", path.display(), err),
+                        );
+                        continue;
+                    }
+                }
+            }
+            _ => TextFill::Flat(get_colour(&top_button)),
+        };
+        let size = size_spin.get_value() as f32;
+        let path = get_cache_dir().map(|dir| get_text_target_path(&dir));
+        break Some(match path {
+            Some(path) => write_text_target(&text, &font_path, size, &fill, &path)
+                .map(|()| path)
+                .map_err(|err| err.to_string()),
+            None => Err("There is no home directory to save the text in".to_string()),
+        });
+    };
+    dialog.close();
+
+    result
+}
+
+/// A library photo shown in the `LibraryBrowser`.
+pub struct BrowserItem {
+    pub path: PathBuf,
+    pub name: String,
+    /// Hue and lightness of the photo's dominant colour, see
+    /// `get_hue_lightness`.
+    pub colour: (Option<f64>, f64),
+    pub aspect: f64,
+    pub excluded: bool,
+    pub pinned: bool,
+    pub child: gtk::FlowBoxChild,
+    pub label: gtk::Label,
+}
+
+/// Shows every photo in the library, filtered by name and sorted by name,
+/// dominant colour or aspect. Selected photos can be excluded or included
+/// again, and after a render each shows how many cells it fills.
+#[derive(Clone)]
+pub struct LibraryBrowser {
+    pub container: gtk::Box,
+    pub search_entry: gtk::SearchEntry,
+    pub sort_combo: gtk::ComboBoxText,
+    pub flow_box: gtk::FlowBox,
+    pub exclude_button: gtk::Button,
+    pub include_button: gtk::Button,
+    pub dedupe_button: gtk::Button,
+    pub items: Rc<RefCell<Vec<BrowserItem>>>,
+    /// Cells filled by each photo in the last mosaic, if there is one.
+    pub usage: Rc<RefCell<Option<HashMap<PathBuf, usize>>>>,
+}
+
+impl Default for LibraryBrowser {
+    fn default() -> Self {
+        LibraryBrowser::new()
+    }
+}
+
+impl LibraryBrowser {
+    pub fn new() -> Self {
+        let search_entry = gtk::SearchEntry::new();
+        search_entry.set_hexpand(true);
+
+        let sort_combo = gtk::ComboBoxText::new();
+        sort_combo.append(Some("name"), "Sort by Name");
+        sort_combo.append(Some("colour"), "Sort by Colour");
+        sort_combo.append(Some("aspect"), "Sort by Aspect");
+        sort_combo.set_active_id(Some("name"));
+
+        let flow_box = gtk::FlowBox::new();
+        flow_box.set_selection_mode(gtk::SelectionMode::Multiple);
+        flow_box.set_valign(gtk::Align::Start);
+        flow_box.set_homogeneous(true);
+
+        let scrolled_window = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
+        scrolled_window.set_vexpand(true);
+        scrolled_window.add(&flow_box);
+
+        let exclude_button = gtk::Button::with_label("Exclude Selected");
+        let include_button = gtk::Button::with_label("Include Selected");
+        let dedupe_button = gtk::Button::with_label("Exclude Duplicates");
+        dedupe_button.set_tooltip_text(Some(
+            "Keep only the largest copy of photos found more than once",
+        ));
+
+        let toolbar = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        toolbar.add(&search_entry);
+        toolbar.add(&sort_combo);
+        toolbar.add(&exclude_button);
+        toolbar.add(&include_button);
+        toolbar.add(&dedupe_button);
+
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        container.add(&toolbar);
+        container.add(&scrolled_window);
+
+        let browser = LibraryBrowser {
+            container,
+            search_entry,
+            sort_combo,
+            flow_box,
+            exclude_button,
+            include_button,
+            dedupe_button,
+            items: Rc::new(RefCell::new(Vec::new())),
+            usage: Rc::new(RefCell::new(None)),
+        };
+
+        browser
+            .search_entry
+            .connect_search_changed(clone!(@strong browser => move |_| {
+                browser.refresh();
+            }));
+        browser
+            .sort_combo
+            .connect_changed(clone!(@strong browser => move |_| {
+                browser.refresh();
+            }));
+
+        browser
+    }
+
+    /// Shows the photos of a newly loaded library.
+    pub fn set_library(&self, library: &Library) {
+        let items = library
+            .pics_data
+            .iter()
+            .map(|pic_data| {
+                let label = gtk::Label::new(None);
+                label.set_max_width_chars(12);
+                label.set_ellipsize(pango::EllipsizeMode::Middle);
+
+                let content = gtk::Box::new(gtk::Orientation::Vertical, 2);
+                content.add(&gtk::Image::from_pixbuf(Some(&get_pixbuf(
+                    &pic_data.preview,
+                ))));
+                content.add(&label);
+                let child = gtk::FlowBoxChild::new();
+                child.add(&content);
+                child.show_all();
+
+                BrowserItem {
+                    path: pic_data.path.clone(),
+                    name: pic_data
+                        .path
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                    colour: get_hue_lightness(get_dominant_colour(&pic_data.preview)),
+                    aspect: pic_data.aspect,
+                    excluded: false,
+                    pinned: false,
+                    child,
+                    label,
+                }
+            })
+            .collect();
+        *self.items.borrow_mut() = items;
+        *self.usage.borrow_mut() = None;
+
+        self.update_labels(library);
+        self.refresh();
+    }
+
+    /// Shows how many cells each photo fills in the mosaic just made.
+    pub fn set_usage(&self, match_data: &[MatchData]) {
+        *self.usage.borrow_mut() = Some(get_usage_counts(match_data));
+        self.show_labels();
+    }
+
+    /// Catches up with photos excluded or pinned since the last update.
+    pub fn update_labels(&self, library: &Library) {
+        for item in self.items.borrow_mut().iter_mut() {
+            item.excluded = library.is_excluded(&item.path);
+            item.pinned = library.is_pinned(&item.path);
+        }
+        self.show_labels();
+    }
+
+    fn show_labels(&self) {
+        let usage = self.usage.borrow();
+        for item in self.items.borrow().iter() {
+            let status = if item.excluded {
+                "Excluded".to_string()
+            } else {
+                let used = match usage
+                    .as_ref()
+                    .map(|usage| usage.get(&item.path).copied().unwrap_or(0))
+                {
+                    Some(0) => "Unused".to_string(),
+                    Some(1) => "Used Once".to_string(),
+                    Some(count) => format!("Used {} Times", count),
+                    None => String::new(),
+                };
+                match (item.pinned, used.is_empty()) {
+                    (true, true) => "Pinned".to_string(),
+                    (true, false) => format!("Pinned, {}", used),
+                    (false, _) => used,
+                }
+            };
+            item.label.set_text(&format!("{}\n{}", item.name, status));
+        }
+    }
+
+    /// Lays the photos out again after the search or sort order changed.
+    pub fn refresh(&self) {
+        for child in self.flow_box.get_children() {
+            self.flow_box.remove(&child);
+        }
+
+        let search = self.search_entry.get_text().to_lowercase();
+        let mut items = self.items.borrow_mut();
+        match self.sort_combo.get_active_id().as_deref() {
+            // Colourful photos first, around the colour wheel, then greys
+            // from dark to light.
+            Some("colour") => items.sort_by(|a, b| match (a.colour, b.colour) {
+                ((Some(a), _), (Some(b), _)) => a.partial_cmp(&b).unwrap(),
+                ((Some(_), _), (None, _)) => Ordering::Less,
+                ((None, _), (Some(_), _)) => Ordering::Greater,
+                ((None, a), (None, b)) => a.partial_cmp(&b).unwrap(),
+            }),
+            Some("aspect") => items.sort_by(|a, b| a.aspect.partial_cmp(&b.aspect).unwrap()),
+            _ => items.sort_by(|a, b| a.name.cmp(&b.name)),
+        }
+        for item in items.iter() {
+            if item.name.to_lowercase().contains(&search) {
+                self.flow_box.add(&item.child);
+            }
+        }
+    }
+
+    /// Paths of the photos currently selected.
+    pub fn get_selected_paths(&self) -> Vec<PathBuf> {
+        let selected = self.flow_box.get_selected_children();
+        self.items
+            .borrow()
+            .iter()
+            .filter(|item| selected.contains(&item.child))
+            .map(|item| item.path.clone())
+            .collect()
+    }
+}
+
+/// Number of worst matched cells listed by `CoveragePanel`.
+const COVERAGE_WORST_CELLS: usize = 20;
+
+/// Largest side of the target with its poorly covered cells shown in
+/// `CoveragePanel`.
+const COVERAGE_OVERLAY_SIZE: u32 = 320;
+
+/// Plots the colours of the library against those of the target's cells,
+/// shows which cells no photo covers well and lists the worst matched cells
+/// and the colours photos should be added in.
+#[derive(Clone)]
+pub struct CoveragePanel {
+    pub container: gtk::Box,
+    pub analyse_button: gtk::Button,
+    pub summary_label: gtk::Label,
+    pub plot_image: gtk::Image,
+    pub overlay_image: gtk::Image,
+    pub worst_list: gtk::ListBox,
+    pub wanted_list: gtk::ListBox,
+    pub save_wanted_button: gtk::Button,
+    /// Colours the library lacks for the photo last analysed.
+    pub gaps: Rc<RefCell<Option<GapReport>>>,
+}
+
+impl Default for CoveragePanel {
+    fn default() -> Self {
+        CoveragePanel::new()
     }
 }
 
+impl CoveragePanel {
+    pub fn new() -> Self {
+        let analyse_button = gtk::Button::with_label("Analyse Coverage");
+        analyse_button.set_tooltip_text(Some(
+            "Compare the library with the photo using the current settings",
+        ));
+        let summary_label = gtk::Label::new(Some("Library green, photo magenta"));
+        summary_label.set_halign(gtk::Align::Start);
+
+        let toolbar = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        toolbar.add(&analyse_button);
+        toolbar.add(&summary_label);
+
+        let plot_image = gtk::Image::new();
+        plot_image.set_valign(gtk::Align::Start);
+        let overlay_image = gtk::Image::new();
+        overlay_image.set_valign(gtk::Align::Start);
+        let images = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        images.add(&plot_image);
+        images.add(&overlay_image);
+
+        let worst_list = gtk::ListBox::new();
+        worst_list.set_selection_mode(gtk::SelectionMode::None);
+        let worst_window = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
+        worst_window.set_hexpand(true);
+        worst_window.add(&worst_list);
+
+        let wanted_list = gtk::ListBox::new();
+        wanted_list.set_selection_mode(gtk::SelectionMode::None);
+        let wanted_window = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
+        wanted_window.set_vexpand(true);
+        wanted_window.add(&wanted_list);
+        let save_wanted_button = gtk::Button::with_label("Save Wanted List");
+        save_wanted_button.set_sensitive(false);
+        let wanted_box = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        wanted_box.set_hexpand(true);
+        wanted_box.add(&wanted_window);
+        wanted_box.add(&save_wanted_button);
+
+        let lists = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        lists.set_vexpand(true);
+        lists.add(&worst_window);
+        lists.add(&wanted_box);
+
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        container.add(&toolbar);
+        container.add(&images);
+        container.add(&lists);
+
+        CoveragePanel {
+            container,
+            analyse_button,
+            summary_label,
+            plot_image,
+            overlay_image,
+            worst_list,
+            wanted_list,
+            save_wanted_button,
+            gaps: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// Shows `report` on the cells of `input`, the target it was made from.
+    pub fn set_report(&self, report: &CoverageReport, input: &ImageBuffer<Rgb<u8>, Vec<u8>>) {
+        self.summary_label.set_text(&format!(
+            "{} of {} cells have no picture within \u{394}E {}",
+            report.get_poor_count(),
+            report.cells.len(),
+            POOR_COVERAGE_DELTA_E
+        ));
+        self.plot_image
+            .set_from_pixbuf(Some(&get_pixbuf(&render_coverage_plot(report))));
+
+        let overlay = render_coverage_overlay(input, report);
+        let scale = COVERAGE_OVERLAY_SIZE as f64 / overlay.width().max(overlay.height()) as f64;
+        let overlay = resize(
+            &overlay,
+            ((overlay.width() as f64 * scale) as u32).max(1),
+            ((overlay.height() as f64 * scale) as u32).max(1),
+            image::FilterType::Triangle,
+        );
+        self.overlay_image
+            .set_from_pixbuf(Some(&get_pixbuf(&overlay)));
+
+        for row in self.worst_list.get_children() {
+            self.worst_list.remove(&row);
+        }
+        for cell in report.get_worst_cells(COVERAGE_WORST_CELLS) {
+            let label = gtk::Label::new(Some(&format!(
+                "{}x{} at {}, {}: Score {:.3}, \u{394}E {:.1}",
+                cell.width, cell.height, cell.x, cell.y, cell.score, cell.delta_e
+            )));
+            label.set_halign(gtk::Align::Start);
+            self.worst_list.add(&label);
+        }
+        self.worst_list.show_all();
+
+        let gaps = get_gap_report(report, report.get_score_quantile(DEFAULT_GAP_QUANTILE));
+        for row in self.wanted_list.get_children() {
+            self.wanted_list.remove(&row);
+        }
+        for swatch in gaps.swatches.iter() {
+            let [r, g, b] = swatch.colour.data;
+            let label = gtk::Label::new(Some(&format!(
+                "#{:02x}{:02x}{:02x}: Wanted by {} Cells",
+                r, g, b, swatch.cells
+            )));
+            let row = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+            row.add(&gtk::Image::from_pixbuf(Some(&get_pixbuf(
+                &render_swatches(std::slice::from_ref(swatch), 24),
+            ))));
+            row.add(&label);
+            self.wanted_list.add(&row);
+        }
+        self.wanted_list.show_all();
+        self.save_wanted_button
+            .set_sensitive(!gaps.swatches.is_empty());
+        *self.gaps.borrow_mut() = Some(gaps);
+    }
+}
+
+/// The last mosaic created, kept so its tiles can still be swapped.
+pub struct Mosaic {
+    /// The target file, before `get_scaled_input`.
+    pub target: PathBuf,
+    pub input: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    /// Transparency of `input`, if it has any.
+    pub alpha: Option<Alpha>,
+    /// The mask the cells were laid out in, if there was one.
+    pub mask: Option<PathBuf>,
+    /// The mosaic as saved, see `finish_mosaic`.
+    pub output: DynamicImage,
+    pub match_data: Vec<MatchData>,
+    /// Where the mosaic is saved, or `None` if it hasn't been yet, as when
+    /// opened from a project saved without one.
+    pub path: Option<PathBuf>,
+    pub config: MosaicConfig,
+}
+
+/// Number of alternatives offered when a tile is clicked.
+const SWAP_CANDIDATES: usize = 8;
+
+const EXCLUDE_RESPONSE: gtk::ResponseType = gtk::ResponseType::Other(1);
+const PIN_RESPONSE: gtk::ResponseType = gtk::ResponseType::Other(2);
+
+/// Offers the best alternatives for the cell of `mosaic` containing `x`, `y`
+/// and, if one is picked, puts it in place and saves the mosaic again, first
+/// asking where if it has never been saved. The picked photo can also be
+/// pinned, or excluded from later mosaics.
+fn show_swap_dialog(
+    window: &gtk::ApplicationWindow,
+    preview: &Preview,
+    mosaic: &RefCell<Option<Mosaic>>,
+    pics_data: &Mutex<Library>,
+    x: u32,
+    y: u32,
+) {
+    let (index, cell, crop, candidates) = {
+        // Tiles can't be swapped while the library is busy creating a mosaic.
+        // It is only held while finding the candidates, not while the dialog
+        // is open.
+        let library = match pics_data.try_lock() {
+            Ok(library) => library,
+            Err(_) => return,
+        };
+        let mosaic = mosaic.borrow();
+        let mosaic = match mosaic.as_ref() {
+            Some(mosaic) => mosaic,
+            None => return,
+        };
+        let index = mosaic.match_data.iter().position(|m| {
+            x >= m.x && y >= m.y && x < m.x + m.tile.width() && y < m.y + m.tile.height()
+        });
+        let index = match index {
+            Some(index) => index,
+            None => return,
+        };
+
+        let m = &mosaic.match_data[index];
+        let (width, height) = m.tile.dimensions();
+        let crop = get_cell(&mosaic.input, m.x, m.y, width, height);
+        let (aspect, thumbnail) = get_cell_thumbnail(&crop, &library, &mosaic.config);
+        let candidates = find_top_matches(
+            aspect,
+            &thumbnail,
+            &library,
+            &mosaic.config.score,
+            mosaic.config.transforms,
+            SWAP_CANDIDATES,
+        );
+        (index, (m.x, m.y, width, height), crop, candidates)
+    };
+    let (cell_x, cell_y, width, height) = cell;
+
+    let dialog = gtk::Dialog::with_buttons(
+        Some("Swap Tile"),
+        Some(window),
+        gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+        &[
+            ("Exclude", EXCLUDE_RESPONSE),
+            ("Use and Pin", PIN_RESPONSE),
+            ("Use", gtk::ResponseType::Ok),
+            ("Cancel", gtk::ResponseType::Cancel),
+        ],
+    );
+    let list_box = gtk::ListBox::new();
+    let preview_scale = 96.0 / width.max(height) as f64;
+    let preview_width = ((width as f64 * preview_scale) as u32).max(1);
+    let preview_height = ((height as f64 * preview_scale) as u32).max(1);
+    for candidate in candidates.iter() {
+        let row = gtk::Box::new(gtk::Orientation::Horizontal, 12);
+        if let Ok(tile) = get_tile(
+            &candidate.path,
+            candidate.transform,
+            preview_width,
+            preview_height,
+        ) {
+            row.add(&gtk::Image::from_pixbuf(Some(&get_pixbuf(&tile))));
+        }
+        let name = candidate
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let in_use = candidate.path == mosaic.borrow().as_ref().unwrap().match_data[index].path;
+        let label = format!(
+            "{}{}\nScore {:.4}",
+            name,
+            if in_use { " (current)" } else { "" },
+            candidate.score
+        );
+        row.add(&gtk::Label::new(Some(&label)));
+        list_box.add(&row);
+    }
+    list_box.select_row(list_box.get_row_at_index(0).as_ref());
+
+    let scrolled_window = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
+    scrolled_window.set_min_content_height(400);
+    scrolled_window.add(&list_box);
+    dialog.get_content_area().add(&scrolled_window);
+    dialog.show_all();
+
+    let response = dialog.run();
+    let selected = list_box
+        .get_selected_row()
+        .map(|row| row.get_index() as usize);
+    dialog.close();
+    let candidate = match selected {
+        Some(selected) => &candidates[selected],
+        None => return,
+    };
+    if response == EXCLUDE_RESPONSE || response == PIN_RESPONSE {
+        let mut library = match pics_data.try_lock() {
+            Ok(library) => library,
+            Err(_) => return show_error(window, "The library is busy, try again once it is done"),
+        };
+        if response == EXCLUDE_RESPONSE {
+            library.exclude(candidate.path.clone());
+            return;
+        }
+        library.pin(candidate.path.clone());
+    } else if response != gtk::ResponseType::Ok {
+        return;
+    }
+
+    let path = {
+        let mut current = mosaic.borrow_mut();
+        let current = current.as_mut().unwrap();
+        let tile = match get_cell_tile(&candidate.path, candidate.transform, &crop, &current.config)
+        {
+            Ok(tile) => tile,
+            Err(err) => {
+                show_error(
+                    window,
+                    &format!("Couldn't open {}: {}", candidate.path.display(), err),
+                );
+                return;
+            }
+        };
+        current.match_data[index] = MatchData {
+            x: cell_x,
+            y: cell_y,
+            path: candidate.path.clone(),
+            transform: candidate.transform,
+            tile,
+        };
+        // Rendered and shown again as a whole, since with grout the cell's
+        // shadow falls on its neighbours.
+        let output = render(
+            &current.input,
+            &current.match_data,
+            current.config.grout.as_ref(),
+        );
+        current.output = finish_mosaic(
+            &output,
+            &current.match_data,
+            current.alpha.as_ref(),
+            &current.config,
+        );
+        preview.update_image(&flatten(&current.output, current.config.background));
+        current.path.clone()
+    };
+
+    // Without anywhere to save it the swap is only kept in the preview.
+    let path = match path.or_else(|| choose_mosaic_path(window)) {
+        Some(path) => path,
+        None => return,
+    };
+    let mut current = mosaic.borrow_mut();
+    let current = current.as_mut().unwrap();
+    let path = get_output_path(&path, &current.config);
+    let result = save_mosaic(&current.output, &path, &NoProgress);
+    match result {
+        Ok(()) => current.path = Some(path),
+        Err(err) => show_error(
+            window,
+            &format!("Couldn't save {}: {}", path.display(), err),
+        ),
+    }
+}
+
+/// Asks where to save a mosaic that hasn't been saved yet.
+fn choose_mosaic_path(window: &gtk::ApplicationWindow) -> Option<PathBuf> {
+    let file_chooser = gtk::FileChooserDialog::new(
+        Some("Save Photo Mosaic"),
+        Some(window),
+        gtk::FileChooserAction::Save,
+    );
+    file_chooser.add_buttons(&[
+        ("Save", gtk::ResponseType::Ok),
+        ("Cancel", gtk::ResponseType::Cancel),
+    ]);
+    file_chooser.set_do_overwrite_confirmation(true);
+
+    let response = file_chooser.run();
+    let path = file_chooser.get_filename();
+    file_chooser.close();
+    if response == gtk::ResponseType::Ok {
+        path
+    } else {
+        None
+    }
+}
+
+/// Asks for a project file to open or save.
+fn choose_project_path(
+    window: &gtk::ApplicationWindow,
+    title: &str,
+    action: gtk::FileChooserAction,
+) -> Option<PathBuf> {
+    let file_chooser = gtk::FileChooserDialog::new(Some(title), Some(window), action);
+    file_chooser.add_buttons(&[
+        (title, gtk::ResponseType::Ok),
+        ("Cancel", gtk::ResponseType::Cancel),
+    ]);
+    let filter = gtk::FileFilter::new();
+    filter.set_name(Some("Mosaic Projects"));
+    filter.add_pattern("*.mosaic");
+    file_chooser.add_filter(&filter);
+    if action == gtk::FileChooserAction::Save {
+        file_chooser.set_do_overwrite_confirmation(true);
+        file_chooser.set_current_name("Untitled.mosaic");
+    }
+
+    let response = file_chooser.run();
+    let path = file_chooser.get_filename();
+    file_chooser.close();
+    if response == gtk::ResponseType::Ok {
+        path
+    } else {
+        None
+    }
+}
+
+/// Saves `mosaic`, with the exclusions and pins of `library`, as a project.
+fn write_project(mosaic: &Mosaic, library: &Library, path: &Path) -> io::Result<()> {
+    let mut project = Project::new(library, &mosaic.target, &mosaic.config, &mosaic.match_data);
+    project.mask = mosaic.mask.clone();
+    project.output = mosaic.path.clone();
+    save_project(&project, path)
+}
+
+/// The target photo, shared with the threads creating mosaics from it.
+type SharedInput = Arc<Mutex<Option<Target>>>;
+
 pub struct MainView {
     pub container: gtk::Grid,
 
-    pub pics_data: Arc<Mutex<Vec<PicData>>>,
+    pub pics_data: Arc<Mutex<Library>>,
+    pub thumb_size_combo: gtk::ComboBoxText,
     pub pics_data_chooser_button: gtk::FileChooserButton,
     pub pics_data_progress: gtk::ProgressBar,
 
-    pub input: Arc<Mutex<Option<ImageBuffer<Rgb<u8>, Vec<u8>>>>>,
+    pub input: SharedInput,
+    pub input_path: Rc<RefCell<Option<PathBuf>>>,
     pub input_chooser_button: gtk::FileChooserButton,
     pub input_progress: gtk::ProgressBar,
 
+    pub preview: Preview,
+    pub mosaic: Rc<RefCell<Option<Mosaic>>>,
+    /// Where the mosaic was last saved or opened as a project.
+    pub project_path: Rc<RefCell<Option<PathBuf>>>,
+
     pub output_chooser_button: gtk::Button,
     pub match_data_progress: gtk::ProgressBar,
+    pub cancel_button: gtk::Button,
+
+    pub settings: SettingsPanel,
+    pub browser: LibraryBrowser,
+    pub coverage: CoveragePanel,
 }
 
 impl MainView {
-    pub fn new(window: &gtk::ApplicationWindow) -> Self {
-        let pics_data = Arc::new(Mutex::new(Vec::new()));
+    pub fn new(window: &gtk::ApplicationWindow, header: &Header) -> Self {
+        let pics_data = Arc::new(Mutex::new(Library::new(DEFAULT_THUMB_SIZE)));
+        let input: SharedInput = Arc::new(Mutex::new(None));
+        let input_path: Rc<RefCell<Option<PathBuf>>> = Rc::new(RefCell::new(None));
+
+        let current_cancel = Rc::new(RefCell::new(CancelToken::new()));
+        let cancel_button = gtk::Button::with_label("Cancel");
+        cancel_button.set_sensitive(false);
+        cancel_button.connect_clicked(clone!(@weak current_cancel => move |_| {
+            current_cancel.borrow().cancel();
+        }));
+
+        let output_chooser_button = gtk::Button::with_label("Create Photo Mosaic");
+        output_chooser_button.set_sensitive(false);
+        // Creating is only allowed with a photo and a library with something
+        // usable in it, and while no other mosaic is being created.
+        let update_create_button = Rc::new(
+            clone!(@weak pics_data, @weak input, @weak cancel_button, @weak output_chooser_button => move || {
+                // The library stays locked while a mosaic is being created.
+                let ready = !cancel_button.get_sensitive()
+                    && input.lock().unwrap().is_some()
+                    && pics_data.try_lock().map_or(false, |library| library.usable_count() > 0);
+                output_chooser_button.set_sensitive(ready);
+                if ready {
+                    output_chooser_button.set_tooltip_text(None);
+                } else {
+                    output_chooser_button.set_tooltip_text(Some("Load pictures and select a photo first"));
+                }
+            }),
+        );
+
+        let browser = LibraryBrowser::new();
+        browser.exclude_button.connect_clicked(clone!(@strong browser, @weak pics_data, @strong update_create_button, @weak window => move |_| {
+            match pics_data.try_lock() {
+                Ok(mut library) => {
+                    for path in browser.get_selected_paths() {
+                        library.exclude(path);
+                    }
+                    browser.update_labels(&library);
+                }
+                Err(_) => return show_error(&window, "Wait for the mosaic being created to finish first"),
+            }
+            update_create_button();
+        }));
+        browser.include_button.connect_clicked(clone!(@strong browser, @weak pics_data, @strong update_create_button, @weak window => move |_| {
+            match pics_data.try_lock() {
+                Ok(mut library) => {
+                    for path in browser.get_selected_paths() {
+                        library.include(&path);
+                    }
+                    browser.update_labels(&library);
+                }
+                Err(_) => return show_error(&window, "Wait for the mosaic being created to finish first"),
+            }
+            update_create_button();
+        }));
+
+        browser.dedupe_button.connect_clicked(clone!(@strong browser, @weak pics_data, @strong update_create_button, @weak window => move |_| {
+            let report = match pics_data.try_lock() {
+                Ok(mut library) => {
+                    let groups = library.dedupe(DEFAULT_DUPLICATE_DISTANCE);
+                    browser.update_labels(&library);
+                    format_duplicate_report(&groups)
+                }
+                Err(_) => return show_error(&window, "Wait for the mosaic being created to finish first"),
+            };
+            update_create_button();
+            show_report(&window, "Duplicates", &report);
+        }));
 
         let pics_data_progress = gtk::ProgressBar::new();
         pics_data_progress.set_text(Some("0 Pictures Loaded"));
         pics_data_progress.set_show_text(true);
         pics_data_progress.set_hexpand(true);
 
-        let pics_data_chooser_button = gtk::FileChooserButton::new("Select Picture", gtk::FileChooserAction::SelectFolder);
-        pics_data_chooser_button.connect_file_set(
-            clone!(@weak pics_data, @weak pics_data_progress, @weak window => move |button| {
-                let path = button.get_filename().expect("Couldn't get filename");
-                let total_files = fs::read_dir(&path).unwrap().count();
-                let paths = fs::read_dir(&path).unwrap().map(|x| x.unwrap().path());
+        let thumb_size_combo = gtk::ComboBoxText::new();
+        for size in &["4", "8", "16", "32", "64", "128"] {
+            thumb_size_combo.append(Some(size), &format!("{}x{} Thumbnails", size, size));
+        }
+        thumb_size_combo.set_active_id(Some(&DEFAULT_THUMB_SIZE.to_string()));
+
+        // Loads the pictures in `dirs` as the library, merged into one if
+        // there are several.
+        let load_library = Rc::new(
+            clone!(@weak pics_data, @weak pics_data_progress, @weak thumb_size_combo, @strong update_create_button, @strong browser, @weak window => move |dirs: Vec<PathBuf>| {
+                let thumb_size: u32 = thumb_size_combo.get_active_id().unwrap().parse().unwrap();
                 let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
-                let local_pics_data = Arc::new(Mutex::new(Vec::new()));
-                
-                thread::spawn(clone!(@weak local_pics_data => move || {
-                    paths.par_bridge().for_each(|path| {
-                        match get_pic_data(path) {
-                            Some(pic_data) => {
-                                local_pics_data.lock().unwrap().push(pic_data);
-                                tx.send(Some(1)).unwrap(); 
-                            },
-                            None => tx.send(Some(0)).unwrap(),
+
+                thread::spawn(clone!(@weak pics_data => move || {
+                    let progress = ChannelProgress::new(tx);
+                    let names: Vec<String> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+                    match get_merged_library(&dirs, thumb_size, &progress) {
+                        Ok(mut library) => {
+                            if library.pics_data.is_empty() {
+                                progress.send(ProgressMessage::Failed(format!(
+                                    "No pictures could be read from {}",
+                                    names.join(", ")
+                                )));
+                            }
+                            // Exclusions and pins outlive reloading, e.g. at another thumbnail size.
+                            let mut pics_data = pics_data.lock().unwrap();
+                            library.excluded = mem::take(&mut pics_data.excluded);
+                            library.pinned = mem::take(&mut pics_data.pinned);
+                            *pics_data = library;
                         }
-                    });                        
-                    tx.send(None).unwrap();
+                        Err(err) => {
+                            progress.send(ProgressMessage::Failed(format!(
+                                "Couldn't read {}: {}",
+                                names.join(", "),
+                                err
+                            )));
+                        }
+                    }
+                    progress.send(ProgressMessage::Finished);
                 }));
 
-                let mut num_processed = 0;
-                let mut num_loaded = 0;
-                rx.attach(None, move |value| match value {
-                    Some(value) => {
-                        num_processed += 1;
-                        num_loaded += value;
-                        
-                        pics_data_progress.set_text(Some(&(num_loaded.to_string() + " Pictures Loaded")));
-                        pics_data_progress.set_fraction(num_processed as f64 / total_files as f64);
+                rx.attach(None, clone!(@strong update_create_button, @strong browser, @weak pics_data => @default-return glib::Continue(false), move |message| match message {
+                    ProgressMessage::LibraryScanned(loaded, progress) => {
+                        pics_data_progress.set_text(Some(&(loaded.to_string() + " Pictures Loaded")));
+                        pics_data_progress.set_fraction(progress.get_fraction());
 
                         glib::Continue(true)
                     }
-                    None => {
-                        *(pics_data.lock().unwrap()) = local_pics_data.lock().unwrap().to_vec();
+                    ProgressMessage::Failed(message) => {
+                        show_error(&window, &message);
+
+                        glib::Continue(true)
+                    }
+                    ProgressMessage::Finished => {
+                        if let Ok(library) = pics_data.try_lock() {
+                            browser.set_library(&library);
+                        }
+                        update_create_button();
 
                         glib::Continue(false)
                     }
-                });
-            })
+                    _ => glib::Continue(true),
+                }));
+            }),
         );
 
-        let input: Arc<Mutex<Option<ImageBuffer<Rgb<u8>, Vec<u8>>>>> = Arc::new(Mutex::new(None));
+        let pics_data_chooser_button =
+            gtk::FileChooserButton::new("Select Picture", gtk::FileChooserAction::SelectFolder);
+        pics_data_chooser_button.connect_file_set(clone!(@strong load_library => move |button| {
+            if let Some(path) = button.get_filename() {
+                load_library(vec![path]);
+            }
+        }));
 
         let input_progress = gtk::ProgressBar::new();
         input_progress.set_text(Some("No Photo Selected"));
@@ -179,185 +1738,523 @@ impl MainView {
 
         let input_chooser_button =
             gtk::FileChooserButton::new("Select Picture", gtk::FileChooserAction::Open);
-        input_chooser_button.connect_file_set(
-            clone!(@weak input, @weak input_progress => move |button| {
-                let path = button.get_filename().unwrap();
-                println!("You selected: {:?}", path);
-                *input.lock().unwrap() = Some(image::open(path).unwrap().to_rgb());
+        let preview = Preview::new();
+        let mosaic = Rc::new(RefCell::new(None));
+        preview.connect_clicked(clone!(@weak mosaic, @weak pics_data, @strong preview, @strong browser, @weak window => move |x, y| {
+            show_swap_dialog(&window, &preview, &mosaic, &pics_data, x, y);
+            let library = pics_data.try_lock();
+            if let Ok(library) = library {
+                browser.update_labels(&library);
+            }
+        }));
+
+        let set_input = Rc::new(
+            clone!(@weak input, @weak input_path, @weak input_progress, @strong preview, @strong update_create_button, @weak window => move |path: PathBuf| {
+                let input_data = match open_target(&path) {
+                    Ok(input_data) => input_data,
+                    Err(err) => {
+                        show_error(&window, &format!("Couldn't open {}: {}", path.display(), err));
+                        return;
+                    }
+                };
+                *input_path.borrow_mut() = Some(path);
+                preview.set_image(&input_data.image);
+                *input.lock().unwrap() = Some(input_data);
                 input_progress.set_text(Some("Photo Selected"));
                 input_progress.set_fraction(1.0);
-            })
+                update_create_button();
+            }),
+        );
+        input_chooser_button.connect_file_set(clone!(@strong set_input => move |button| {
+            if let Some(path) = button.get_filename() {
+                set_input(path);
+            }
+        }));
+
+        let text_button = gtk::Button::with_label("Use Text…");
+        text_button.set_tooltip_text(Some("Make a mosaic in the shape of some text"));
+        text_button.connect_clicked(
+            clone!(@weak input_chooser_button, @strong set_input, @weak window => move |_| {
+                match show_text_dialog(&window) {
+                    Some(Ok(path)) => {
+                        input_chooser_button.set_filename(&path);
+                        set_input(path);
+                    }
+                    Some(Err(message)) => show_error(&window, &message),
+                    None => {}
+                }
+            }),
         );
 
+        // Folders dropped anywhere on the window are loaded as the library
+        // and a dropped file is used as the photo, as if chosen with the
+        // buttons.
+        let drop_paths = clone!(@weak pics_data_chooser_button, @weak input_chooser_button, @strong load_library, @strong set_input => move |paths: Vec<PathBuf>| {
+            let (dirs, files): (Vec<PathBuf>, Vec<PathBuf>) = paths.into_iter().partition(|path| path.is_dir());
+            if let Some(dir) = dirs.first() {
+                pics_data_chooser_button.set_filename(dir);
+                load_library(dirs);
+            }
+            if let Some(file) = files.into_iter().next() {
+                input_chooser_button.set_filename(&file);
+                set_input(file);
+            }
+        });
+        connect_drop(window, drop_paths);
+
         let match_data_progress = gtk::ProgressBar::new();
         match_data_progress.set_text(Some("0 Tiles Placed"));
         match_data_progress.set_show_text(true);
         match_data_progress.set_hexpand(true);
 
-        let output_chooser_button = gtk::Button::with_label("Create Photo Mosaic");
-        output_chooser_button.connect_clicked(clone!(@weak input, @weak pics_data, @weak match_data_progress, @weak window => move |button| {
-            let pics_dataz = pics_data.lock().unwrap();
-            println!("I unwrapped pics_data, it has {} elements", pics_dataz.len());
+        let settings = SettingsPanel::new();
+
+        output_chooser_button.connect_clicked(clone!(@strong browser, @weak input, @weak pics_data, @weak match_data_progress, @strong settings, @weak current_cancel, @weak cancel_button, @strong preview, @weak mosaic, @weak input_path, @strong update_create_button, @weak window => move |_| {
             let file_chooser = gtk::FileChooserDialog::new(
                 Some("Create Photo Mosaic"),
                 Some(&window),
                 gtk::FileChooserAction::Save
-            ); 
+            );
             file_chooser.add_buttons(&[
                 ("Create", gtk::ResponseType::Ok),
                 ("Cancel", gtk::ResponseType::Cancel),
             ]);
-            file_chooser.connect_response(clone!(@weak input, @weak pics_data, @weak match_data_progress => move |file_chooser, response| {
-                if response == gtk::ResponseType::Ok {
-                    let input_data = input.lock().unwrap().as_ref().clone().unwrap().clone();
-                    let path = file_chooser.get_filename().expect("Couldn't get filename");
-                    println!("You selected: {:?}", path);
-                    println!("Create the output!");
-
-                        let mut x_rulers = Vec::new();
-
-                        let mut pixels = 0;
-                        let distribution = Uniform::new(120, 320);
-                        let mut rng = rand::thread_rng();
-                        loop {
-                            let step = distribution.sample(&mut rng);
-
-                            if pixels + step > input_data.width() {
-                                break;
-                            }
+            file_chooser.connect_response(clone!(@strong browser, @weak input, @weak pics_data, @weak match_data_progress, @strong settings, @weak current_cancel, @weak cancel_button, @strong preview, @weak mosaic, @weak input_path, @strong update_create_button, @weak window => move |file_chooser, response| {
+                let path = file_chooser.get_filename();
+                file_chooser.close();
+                if response != gtk::ResponseType::Ok {
+                    return;
+                }
+                let path = match path {
+                    Some(path) => path,
+                    None => return show_error(&window, "Choose where to save the mosaic"),
+                };
+                let input_data = match input.lock().unwrap().as_ref() {
+                    Some(input_data) => input_data.clone(),
+                    None => return show_error(&window, "Select a photo to turn into a mosaic first"),
+                };
+                let usable_count = pics_data.try_lock().ok().map(|library| library.usable_count());
+                match usable_count {
+                    Some(0) => return show_error(&window, "Load a folder with usable pictures first"),
+                    Some(_) => {}
+                    None => return show_error(&window, "Wait for the mosaic being created to finish first"),
+                }
 
-                            pixels += step;
-                            x_rulers.push(pixels);
-                        }
+                let config = settings.get_config();
+                if let Err(err) = save_settings(&config) {
+                    eprintln!("Couldn't save settings: {}", err);
+                }
+                let path = get_output_path(&path, &config);
+                let target = match settings.get_target(&input_data, &config) {
+                    Ok(target) => target,
+                    Err(message) => return show_error(&window, &message),
+                };
 
-                        let mut x_remaining = input_data.width() - pixels;
-                        while x_remaining > 0 {
-                            for i in 0..x_rulers.len() {
-                                for j in i..x_rulers.len() {
-                                    x_rulers[j] += 1;
-                                }
-
-                                x_remaining -= 1;
-                                if x_remaining <= 0 {
-                                    break;
-                                }
-                            }
-                        }
+                let (x_rulers, y_rulers) = get_layout(target.image.width(), target.image.height(), &config);
+                let cells = get_visible_cells(&x_rulers, &y_rulers, target.get_mask(), config.mask_threshold);
+                let Target { image: input_data, alpha, .. } = target;
 
-                        x_rulers.insert(0, 0);
+                preview.set_image(&input_data);
 
-                        let mut y_rulers = Vec::new();
+                // Kept with the finished mosaic so its tiles can be swapped later.
+                let mosaic_target = input_path.borrow().clone().unwrap_or_default();
+                let mosaic_input = input_data.clone();
+                let mosaic_alpha = alpha.clone();
+                let mosaic_mask = settings.get_mask_path();
+                let mosaic_path = path.clone();
+                let mosaic_config = config.clone();
 
-                        let mut pixels = 0;
-                        loop {
-                            let step = distribution.sample(&mut rng);
+                let cancel = CancelToken::new();
+                *current_cancel.borrow_mut() = cancel.clone();
+                cancel_button.set_sensitive(true);
+                update_create_button();
 
-                            if pixels + step > input_data.height() {
-                                break;
-                            }
+                let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
 
-                            pixels += step;
-                            y_rulers.push(pixels);
-                        }
+                thread::spawn(clone!(@strong cancel => move || {
+                    let progress = ChannelProgress::new(tx);
+                    let pics_data = pics_data.lock().unwrap();
 
-                        let mut y_remaining = input_data.height() - pixels;
-                        while y_remaining > 0 {
-                            for i in 0..y_rulers.len() {
-                                for j in i..y_rulers.len() {
-                                    y_rulers[j] += 1;
-                                }
-
-                                y_remaining -= 1;
-                                if y_remaining <= 0 {
-                                    break;
-                                }
+                    let match_data = get_match_data(
+                        &input_data,
+                        &pics_data,
+                        &cells,
+                        &config,
+                        &cancel,
+                        &progress,
+                    );
+                    match match_data {
+                        Ok(match_data) => {
+                            let output = render(&input_data, &match_data, config.grout.as_ref());
+                            let output = finish_mosaic(&output, &match_data, alpha.as_ref(), &config);
+                            let result = save_mosaic(&output, &path, &progress);
+                            if let Err(err) = result {
+                                progress.send(ProgressMessage::Failed(format!(
+                                    "Couldn't save {}: {}",
+                                    path.display(),
+                                    err
+                                )));
                             }
+                            progress.send(ProgressMessage::Rendered(output, match_data));
                         }
+                        Err(MatchError::Cancelled) => {}
+                        Err(err) => progress.send(ProgressMessage::Failed(err.to_string())),
+                    }
+                    // Unlocked before `Finished` so the library is free again.
+                    drop(pics_data);
+                    progress.send(ProgressMessage::Finished);
+                }));
 
-                        y_rulers.insert(0, 0);
-
-                    let total_tiles = (x_rulers.len() - 1)  * (y_rulers.len() - 1);
-                    println!("Total tiles: {:?}", total_tiles);
-
-                    let local_match_data = Arc::new(Mutex::new(Vec::new()));
-                    let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
-
-                    thread::spawn(clone!(@weak local_match_data, @weak input => move || {
-                        let pics_data = pics_data.lock().unwrap();
-
-                        let x_steps = x_rulers[0..x_rulers.len() - 1].iter().enumerate();
-                        let y_steps = y_rulers[0..y_rulers.len() - 1].iter().enumerate();
-                        x_steps
-                            .cartesian_product(y_steps)
-                            .par_bridge()
-                            .for_each(|((i, &x), (j, &y))| {
-                                let width = x_rulers[i + 1] - x;
-                                let height = y_rulers[j + 1] - y;
-                                println!("{}, {}, {}, {}, {}, {}", i, j, x, y, width, height);
-
-                                let mut crop_img = input.lock().unwrap().as_ref().clone().unwrap().clone();
-                                let crop = crop(&mut crop_img, x, y, width, height).to_image();
-                                let aspect = width as f64 / height as f64;
-                                let thumbnail = resize(&crop, 128, 128, image::FilterType::Lanczos3);
-
-                                let best_match = find_best_match(aspect, &thumbnail, &pics_data);
-                                let best_image = image::open(best_match).unwrap().to_rgb();
-                                let best_resize = resize(&best_image, width, height, image::FilterType::Lanczos3);
-
-                                let match_data = MatchData {
-                                    x: x,
-                                    y: y,
-                                    tile: best_resize,
-                                };
-                                local_match_data.lock().unwrap().push(match_data);
-                                tx.send(Some(1)).unwrap();
-                            });
-                        tx.send(None).unwrap();  
-                    }));
-
-                    let mut count = 0;
-                    rx.attach(None, move |value| match value {
-                        Some(_) => {
-                            count += 1;
-                            match_data_progress.set_text(Some(&(count.to_string() + " Tiles Placed")));
-                            match_data_progress.set_fraction(count as f64 / total_tiles as f64);
-
-                            glib::Continue(true)
+                rx.attach(None, clone!(@strong preview, @strong update_create_button, @strong browser => move |message| match message {
+                    ProgressMessage::LayoutDone(cells) => {
+                        match_data_progress.set_text(Some(&format!("0 of {} Tiles Placed", cells)));
+                        match_data_progress.set_fraction(0.0);
+
+                        glib::Continue(true)
+                    }
+                    ProgressMessage::CellMatched(match_data, progress) => {
+                        preview.place_tile(match_data.x, match_data.y, &match_data.tile);
+
+                        let mut text = progress.done.to_string() + " Tiles Placed";
+                        if let Some(eta) = progress.eta {
+                            text += &format!(", {} Left", format_duration(eta));
                         }
-                        None => {
-                            let mut output = input.lock().unwrap().clone().take().unwrap();
-                            for m in local_match_data.lock().unwrap().iter() {
-                                replace(&mut output, &m.tile, m.x, m.y);
-                            }
-                            image::save_buffer(
-                                &path,
-                                &output,
-                                output.width(),
-                                output.height(),
-                                image::ColorType::RGB(8),
-                            )
-                            .unwrap();
-
-                            glib::Continue(false)
+                        match_data_progress.set_text(Some(&text));
+                        match_data_progress.set_fraction(progress.get_fraction());
+
+                        glib::Continue(true)
+                    }
+                    ProgressMessage::Rendered(output, match_data) => {
+                        preview.set_image(&flatten(&output, mosaic_config.background));
+                        browser.set_usage(&match_data);
+                        *mosaic.borrow_mut() = Some(Mosaic {
+                            target: mosaic_target.clone(),
+                            input: mosaic_input.clone(),
+                            alpha: mosaic_alpha.clone(),
+                            mask: mosaic_mask.clone(),
+                            output,
+                            match_data,
+                            path: Some(mosaic_path.clone()),
+                            config: mosaic_config.clone(),
+                        });
+
+                        glib::Continue(true)
+                    }
+                    ProgressMessage::RenderWritten(path) => {
+                        let name = path.file_name().unwrap_or_default().to_string_lossy();
+                        match_data_progress.set_text(Some(&format!("Saved {}", name)));
+
+                        glib::Continue(true)
+                    }
+                    ProgressMessage::Failed(message) => {
+                        match_data_progress.set_text(Some("Not Saved"));
+                        show_error(&window, &message);
+
+                        glib::Continue(true)
+                    }
+                    ProgressMessage::Finished => {
+                        cancel_button.set_sensitive(false);
+                        update_create_button();
+                        if cancel.is_cancelled() {
+                            match_data_progress.set_text(Some("Cancelled"));
+                            match_data_progress.set_fraction(0.0);
                         }
-                    });
-                }
 
-                file_chooser.close();
+                        glib::Continue(false)
+                    }
+                    _ => glib::Continue(true),
+                }));
             }));
 
             file_chooser.show_all();
         }));
 
+        let coverage = CoveragePanel::new();
+        coverage.analyse_button.connect_clicked(clone!(@strong coverage, @weak input, @weak pics_data, @weak match_data_progress, @strong settings, @weak current_cancel, @weak cancel_button, @strong update_create_button, @weak window => move |_| {
+            let input_data = match input.lock().unwrap().as_ref() {
+                Some(input_data) => input_data.clone(),
+                None => return show_error(&window, "Select a photo to analyse first"),
+            };
+            let usable_count = pics_data.try_lock().ok().map(|library| library.usable_count());
+            match usable_count {
+                Some(0) => return show_error(&window, "Load a folder with usable pictures first"),
+                Some(_) => {}
+                None => return show_error(&window, "Wait for the mosaic being created to finish first"),
+            }
+
+            let config = settings.get_config();
+            let target = match settings.get_target(&input_data, &config) {
+                Ok(target) => target,
+                Err(message) => return show_error(&window, &message),
+            };
+            let (x_rulers, y_rulers) = get_layout(target.image.width(), target.image.height(), &config);
+            let cells = get_visible_cells(&x_rulers, &y_rulers, target.get_mask(), config.mask_threshold);
+            let input_data = target.image;
+
+            let cancel = CancelToken::new();
+            *current_cancel.borrow_mut() = cancel.clone();
+            cancel_button.set_sensitive(true);
+            coverage.analyse_button.set_sensitive(false);
+            update_create_button();
+            match_data_progress.set_text(Some("Analysing Coverage"));
+            match_data_progress.pulse();
+
+            let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+
+            thread::spawn(clone!(@strong cancel => move || {
+                let progress = ChannelProgress::new(tx);
+                let pics_data = pics_data.lock().unwrap();
+                let report = get_coverage(&input_data, &pics_data, &cells, &config, &cancel);
+                if let Some(report) = report {
+                    progress.send(ProgressMessage::CoverageAnalysed(report, input_data));
+                }
+                // Unlocked before `Finished` so the library is free again.
+                drop(pics_data);
+                progress.send(ProgressMessage::Finished);
+            }));
+
+            rx.attach(None, clone!(@strong coverage, @strong update_create_button => move |message| match message {
+                ProgressMessage::CoverageAnalysed(report, input_data) => {
+                    coverage.set_report(&report, &input_data);
+                    match_data_progress.set_text(Some("Coverage Analysed"));
+                    match_data_progress.set_fraction(1.0);
+
+                    glib::Continue(true)
+                }
+                ProgressMessage::Finished => {
+                    cancel_button.set_sensitive(false);
+                    coverage.analyse_button.set_sensitive(true);
+                    update_create_button();
+                    if cancel.is_cancelled() {
+                        match_data_progress.set_text(Some("Cancelled"));
+                        match_data_progress.set_fraction(0.0);
+                    }
+
+                    glib::Continue(false)
+                }
+                _ => glib::Continue(true),
+            }));
+        }));
+
+        coverage.save_wanted_button.connect_clicked(clone!(@strong coverage, @weak window => move |_| {
+            let file_chooser = gtk::FileChooserDialog::new(
+                Some("Save Wanted List"),
+                Some(&window),
+                gtk::FileChooserAction::Save,
+            );
+            file_chooser.add_buttons(&[
+                ("Save", gtk::ResponseType::Ok),
+                ("Cancel", gtk::ResponseType::Cancel),
+            ]);
+            file_chooser.set_do_overwrite_confirmation(true);
+            file_chooser.set_current_name("wanted.txt");
+            let response = file_chooser.run();
+            let path = file_chooser.get_filename();
+            file_chooser.close();
+            let path = match path {
+                Some(path) if response == gtk::ResponseType::Ok => path,
+                _ => return,
+            };
+
+            let text = coverage.gaps.borrow().as_ref().map(format_gap_report).unwrap_or_default();
+            if let Err(err) = fs::write(&path, text) {
+                show_error(&window, &format!("Couldn't save {}: {}", path.display(), err));
+            }
+        }));
+
+        let project_path: Rc<RefCell<Option<PathBuf>>> = Rc::new(RefCell::new(None));
+        let header_bar = header.container.clone();
+
+        let save_project_as = clone!(@weak mosaic, @weak pics_data, @weak project_path, @weak cancel_button, @weak window, @weak header_bar => move || {
+            if mosaic.borrow().is_none() {
+                return;
+            }
+            if cancel_button.get_sensitive() {
+                return show_error(&window, "Wait for the mosaic being created to finish first");
+            }
+            let path = match choose_project_path(&window, "Save", gtk::FileChooserAction::Save) {
+                Some(path) => path,
+                None => return,
+            };
+            let result = match pics_data.try_lock() {
+                Ok(library) => write_project(mosaic.borrow().as_ref().unwrap(), &library, &path),
+                Err(_) => return show_error(&window, "Wait for the mosaic being created to finish first"),
+            };
+            match result {
+                Ok(()) => {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    header_bar.set_subtitle(Some(&name));
+                    *project_path.borrow_mut() = Some(path);
+                }
+                Err(err) => show_error(&window, &format!("Couldn't save {}: {}", path.display(), err)),
+            }
+        });
+        let save_project_as = Rc::new(save_project_as);
+
+        header
+            .save_as_button
+            .connect_clicked(clone!(@strong save_project_as => move |_| {
+                save_project_as();
+            }));
+        header.save_button.connect_clicked(clone!(@weak mosaic, @weak pics_data, @weak project_path, @strong save_project_as, @weak window => move |_| {
+            let path = project_path.borrow().clone();
+            let path = match path {
+                Some(path) => path,
+                None => return save_project_as(),
+            };
+            let library = match pics_data.try_lock() {
+                Ok(library) => library,
+                Err(_) => return show_error(&window, "Wait for the mosaic being created to finish first"),
+            };
+            let result = match mosaic.borrow().as_ref() {
+                Some(mosaic) => write_project(mosaic, &library, &path),
+                None => return,
+            };
+            drop(library);
+            if let Err(err) = result {
+                show_error(&window, &format!("Couldn't save {}: {}", path.display(), err));
+            }
+        }));
+
+        header.open_button.connect_clicked(clone!(@strong browser, @weak pics_data, @weak pics_data_progress, @weak pics_data_chooser_button, @weak thumb_size_combo, @weak input, @weak input_path, @weak input_progress, @weak input_chooser_button, @strong preview, @weak mosaic, @weak project_path, @strong settings, @weak cancel_button, @strong update_create_button, @weak window, @weak header_bar => move |_| {
+            if cancel_button.get_sensitive() {
+                return show_error(&window, "Wait for the mosaic being created to finish first");
+            }
+            let path = match choose_project_path(&window, "Open", gtk::FileChooserAction::Open) {
+                Some(path) => path,
+                None => return,
+            };
+            let project = match load_project(&path) {
+                Ok(project) => project,
+                Err(err) => {
+                    return show_error(&window, &format!("Couldn't open {}: {}", path.display(), err));
+                }
+            };
+
+            settings.set_config(&project.config);
+            settings.set_mask_path(project.mask.as_deref());
+            thumb_size_combo.set_active_id(Some(&project.thumb_size.to_string()));
+            if let Some(dir) = project.library_dirs.first() {
+                pics_data_chooser_button.set_filename(dir);
+            }
+            input_chooser_button.set_filename(&project.target);
+            *input_path.borrow_mut() = Some(project.target.clone());
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            header_bar.set_subtitle(Some(&name));
+            *project_path.borrow_mut() = Some(path);
+
+            let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+
+            thread::spawn(clone!(@weak pics_data, @weak input => move || {
+                let progress = ChannelProgress::new(tx);
+                match get_merged_library(&project.library_dirs, project.thumb_size, &progress) {
+                    Ok(mut library) => {
+                        project.restore_library(&mut library);
+                        *pics_data.lock().unwrap() = library;
+                    }
+                    Err(err) => {
+                        progress.send(ProgressMessage::Failed(format!(
+                            "Couldn't read {}: {}",
+                            project.library_dirs[0].display(),
+                            err
+                        )));
+                    }
+                }
+
+                let target = match open_target(&project.target) {
+                    Ok(target) => target,
+                    Err(err) => {
+                        progress.send(ProgressMessage::Failed(format!(
+                            "Couldn't open {}: {}",
+                            project.target.display(),
+                            err
+                        )));
+                        return progress.send(ProgressMessage::Finished);
+                    }
+                };
+                let Target { image: mosaic_input, alpha, .. } = target.get_scaled(project.config.output_scale);
+                *input.lock().unwrap() = Some(target);
+                match get_project_match_data(&mosaic_input, &project) {
+                    Ok(match_data) => {
+                        let output = render(&mosaic_input, &match_data, project.config.grout.as_ref());
+                        let output = finish_mosaic(&output, &match_data, alpha.as_ref(), &project.config);
+                        progress.send(ProgressMessage::ProjectOpened(Mosaic {
+                            target: project.target.clone(),
+                            input: mosaic_input,
+                            alpha,
+                            mask: project.mask.clone(),
+                            output,
+                            match_data,
+                            path: project.output.clone(),
+                            config: project.config.clone(),
+                        }));
+                    }
+                    Err(err) => {
+                        progress.send(ProgressMessage::Failed(format!(
+                            "Couldn't render the project: {}",
+                            err
+                        )));
+                    }
+                }
+                progress.send(ProgressMessage::Finished);
+            }));
+
+            rx.attach(None, clone!(@strong preview, @strong update_create_button, @strong browser => move |message| match message {
+                ProgressMessage::LibraryScanned(loaded, progress) => {
+                    pics_data_progress.set_text(Some(&(loaded.to_string() + " Pictures Loaded")));
+                    pics_data_progress.set_fraction(progress.get_fraction());
+
+                    glib::Continue(true)
+                }
+                ProgressMessage::ProjectOpened(opened) => {
+                    preview.set_image(&flatten(&opened.output, opened.config.background));
+                    // The project's library was loaded before it was rendered.
+                    if let Ok(library) = pics_data.try_lock() {
+                        browser.set_library(&library);
+                    }
+                    browser.set_usage(&opened.match_data);
+                    input_progress.set_text(Some("Photo Selected"));
+                    input_progress.set_fraction(1.0);
+                    *mosaic.borrow_mut() = Some(opened);
+
+                    glib::Continue(true)
+                }
+                ProgressMessage::Failed(message) => {
+                    show_error(&window, &message);
+
+                    glib::Continue(true)
+                }
+                ProgressMessage::Finished => {
+                    update_create_button();
+
+                    glib::Continue(false)
+                }
+                _ => glib::Continue(true),
+            }));
+        }));
+
         let container = gtk::Grid::new();
-        container.attach(&pics_data_chooser_button, 0, 0, 1, 1);
-        container.attach(&pics_data_progress, 1, 0, 1, 1);
-        container.attach(&input_chooser_button, 0, 1, 1, 1);
-        container.attach(&input_progress, 1, 1, 1, 1);
-        container.attach(&output_chooser_button, 0, 2, 1, 1);
-        container.attach(&match_data_progress, 1, 2, 1, 1);
+        container.attach(&thumb_size_combo, 0, 0, 3, 1);
+        container.attach(&pics_data_chooser_button, 0, 1, 1, 1);
+        container.attach(&pics_data_progress, 1, 1, 1, 1);
+        container.attach(&input_chooser_button, 0, 2, 1, 1);
+        container.attach(&input_progress, 1, 2, 1, 1);
+        container.attach(&text_button, 2, 2, 1, 1);
+        container.attach(&output_chooser_button, 0, 3, 1, 1);
+        container.attach(&match_data_progress, 1, 3, 1, 1);
+        container.attach(&cancel_button, 2, 3, 1, 1);
+        container.attach(&settings.container, 0, 4, 3, 1);
+        let notebook = gtk::Notebook::new();
+        notebook.append_page(&preview.container, Some(&gtk::Label::new(Some("Preview"))));
+        notebook.append_page(&browser.container, Some(&gtk::Label::new(Some("Library"))));
+        notebook.append_page(
+            &coverage.container,
+            Some(&gtk::Label::new(Some("Coverage"))),
+        );
+        container.attach(&notebook, 3, 0, 1, 5);
 
         container.set_row_spacing(12);
+        container.set_column_spacing(6);
         container.set_border_width(6);
         container.set_vexpand(true);
         container.set_hexpand(true);
@@ -366,15 +2263,26 @@ impl MainView {
             container,
 
             pics_data,
+            thumb_size_combo,
             pics_data_chooser_button,
             pics_data_progress,
 
             input,
+            input_path,
             input_chooser_button,
             input_progress,
 
+            preview,
+            mosaic,
+            project_path,
+
             output_chooser_button,
             match_data_progress,
+            cancel_button,
+
+            settings,
+            browser,
+            coverage,
         }
     }
 }
@@ -0,0 +1,186 @@
+use image::imageops::resize;
+use image::{ImageBuffer, Rgb};
+use std::path::PathBuf;
+
+use crate::{get_cell_targets, get_pic_aspect, Library, ScoreConfig, Transform};
+
+/// Size of the thumbnails the whole library is filtered on first.
+pub const COARSE_THUMB_SIZE: u32 = 4;
+
+/// Size of the thumbnails the survivors of the coarse pass are scored at.
+pub const MEDIUM_THUMB_SIZE: u32 = 16;
+
+pub fn get_coarse_thumbnail(
+    thumbnail: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let size = COARSE_THUMB_SIZE.min(thumbnail.width());
+    resize(thumbnail, size, size, image::FilterType::Triangle)
+}
+
+pub fn get_medium_thumbnail(
+    thumbnail: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let size = MEDIUM_THUMB_SIZE.min(thumbnail.width());
+    resize(thumbnail, size, size, image::FilterType::Triangle)
+}
+
+/// How many candidates `find_best_match_cascade` keeps after each pass.
+#[derive(Clone, Debug)]
+pub struct CascadeConfig {
+    /// Candidates kept after scoring the whole library at `COARSE_THUMB_SIZE`.
+    pub coarse_keep: usize,
+    /// Candidates kept after rescoring those at `MEDIUM_THUMB_SIZE`. Only
+    /// these are scored at the library's full thumbnail size.
+    pub medium_keep: usize,
+}
+
+impl Default for CascadeConfig {
+    fn default() -> Self {
+        CascadeConfig {
+            coarse_keep: 64,
+            medium_keep: 8,
+        }
+    }
+}
+
+/// Index into `Library::pics_data` and into the cell targets, with a score.
+type Candidate = (usize, usize, f64);
+
+fn keep_best(candidates: &mut Vec<Candidate>, keep: usize) {
+    if candidates.len() > keep {
+        candidates.select_nth_unstable_by(keep, |a, b| a.2.partial_cmp(&b.2).unwrap());
+        candidates.truncate(keep);
+    }
+}
+
+/// Finds a close match for a cell much faster than `find_best_match` on large
+/// libraries. Every photo is scored on a tiny thumbnail, the best are
+/// rescored on a larger one, and only the final few are compared at the
+/// library's full thumbnail size. The winner is usually, but not always, the
+/// one `find_best_match` would pick. `None` if there is no photo to use.
+pub fn find_best_match_cascade(
+    aspect: f64,
+    thumbnail: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    library: &Library,
+    config: &ScoreConfig,
+    transforms: bool,
+    cascade: &CascadeConfig,
+) -> Option<(PathBuf, Transform)> {
+    let targets = get_cell_targets(aspect, thumbnail, transforms);
+    let coarse_targets: Vec<_> = targets
+        .iter()
+        .map(|target| get_coarse_thumbnail(&target.thumbnail))
+        .collect();
+    let medium_targets: Vec<_> = targets
+        .iter()
+        .map(|target| get_medium_thumbnail(&target.thumbnail))
+        .collect();
+
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for (i, pic_data) in library.pics_data.iter().enumerate() {
+        if library.is_excluded(&pic_data.path) {
+            continue;
+        }
+        for (j, target) in targets.iter().enumerate() {
+            let pic_aspect = get_pic_aspect(pic_data, target.transform);
+            let pic_thumbnail = &pic_data.coarse[target.bucket];
+            let score = config.get_score(aspect, pic_aspect, &coarse_targets[j], pic_thumbnail);
+            candidates.push((i, j, score));
+        }
+    }
+    keep_best(&mut candidates, cascade.coarse_keep);
+
+    for candidate in candidates.iter_mut() {
+        let (i, j, _) = *candidate;
+        let pic_data = &library.pics_data[i];
+        let target = &targets[j];
+        let pic_aspect = get_pic_aspect(pic_data, target.transform);
+        let pic_thumbnail = &pic_data.medium[target.bucket];
+        candidate.2 = config.get_score(aspect, pic_aspect, &medium_targets[j], pic_thumbnail);
+    }
+    keep_best(&mut candidates, cascade.medium_keep);
+
+    let mut best_match = None;
+    let mut best_score = f64::INFINITY;
+    for (i, j, _) in candidates {
+        let pic_data = &library.pics_data[i];
+        let target = &targets[j];
+        let pic_aspect = get_pic_aspect(pic_data, target.transform);
+        let pic_thumbnail = &pic_data.cropped[target.bucket];
+        let bound = best_score;
+        if let Some(score) =
+            config.get_score_bounded(aspect, pic_aspect, &target.thumbnail, pic_thumbnail, bound)
+        {
+            if score < best_score {
+                best_match = Some((pic_data.path.clone(), target.transform));
+                best_score = score;
+            }
+        }
+    }
+
+    best_match
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{find_best_match, get_library, NoProgress};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// A photo of random size with a gradient between two random colours.
+    fn get_random_pic(rng: &mut StdRng) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let width = rng.gen_range(16..64);
+        let height = rng.gen_range(16..64);
+        let from: [u8; 3] = rng.gen();
+        let to: [u8; 3] = rng.gen();
+        ImageBuffer::from_fn(width, height, |x, y| {
+            let t = (x + 2 * y) as f64 / (width + 2 * height - 3) as f64;
+            let mut data = [0; 3];
+            for c in 0..3 {
+                let value = from[c] as f64 * (1.0 - t) + to[c] as f64 * t;
+                data[c] = (value + rng.gen_range(-10.0..10.0)).clamp(0.0, 255.0) as u8;
+            }
+            Rgb { data }
+        })
+    }
+
+    #[test]
+    fn cascade_mostly_agrees_with_full_search() {
+        const CELLS: usize = 50;
+
+        let mut rng = StdRng::seed_from_u64(30);
+        let dir = env::temp_dir().join(format!("mosaic-cascade-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for i in 0..200 {
+            let pic = get_random_pic(&mut rng);
+            pic.save(dir.join(format!("{}.png", i))).unwrap();
+        }
+        let library = get_library(&dir, 32, &NoProgress).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let config = ScoreConfig::default();
+        let cascade = CascadeConfig::default();
+
+        let mut agreed = 0;
+        for _ in 0..CELLS {
+            let cell = get_random_pic(&mut rng);
+            let aspect = cell.width() as f64 / cell.height() as f64;
+            let thumbnail = library.get_thumbnail(&cell);
+            let full = find_best_match(aspect, &thumbnail, &library, &config, true);
+            let fast =
+                find_best_match_cascade(aspect, &thumbnail, &library, &config, true, &cascade);
+            if fast == full {
+                agreed += 1;
+            }
+        }
+        assert!(
+            agreed * 10 >= CELLS * 9,
+            "{} of {} cells agreed",
+            agreed,
+            CELLS
+        );
+    }
+}
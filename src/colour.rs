@@ -0,0 +1,180 @@
+use image::{ImageBuffer, Rgb};
+
+use crate::get_channel_sums;
+
+/// Levels each channel is quantised to when looking for the dominant colour.
+const DOMINANT_LEVELS: usize = 4;
+
+/// Returns the most common colour in `img`: pixels are grouped into coarse
+/// colour bins and the mean of the fullest bin is returned.
+pub fn get_dominant_colour(img: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Rgb<u8> {
+    let mut counts = vec![0u64; DOMINANT_LEVELS * DOMINANT_LEVELS * DOMINANT_LEVELS];
+    let mut sums = vec![[0u64; 3]; counts.len()];
+    for pixel in img.pixels() {
+        let bin = pixel.data.iter().fold(0, |bin, &value| {
+            bin * DOMINANT_LEVELS + value as usize * DOMINANT_LEVELS / 256
+        });
+        counts[bin] += 1;
+        for (sum, &value) in sums[bin].iter_mut().zip(pixel.data.iter()) {
+            *sum += value as u64;
+        }
+    }
+
+    let (bin, &count) = counts
+        .iter()
+        .enumerate()
+        .max_by_key(|&(_, count)| count)
+        .unwrap();
+    let count = count.max(1);
+    Rgb([
+        (sums[bin][0] / count) as u8,
+        (sums[bin][1] / count) as u8,
+        (sums[bin][2] / count) as u8,
+    ])
+}
+
+/// Returns the hue of `colour` in degrees, or `None` if it is too close to
+/// grey to have one, and its lightness from 0.0 to 1.0.
+pub fn get_hue_lightness(colour: Rgb<u8>) -> (Option<f64>, f64) {
+    let [r, g, b] = colour.data;
+    let (r, g, b) = (r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.0;
+    let chroma = max - min;
+    if chroma < 0.05 {
+        return (None, lightness);
+    }
+
+    let hue = if max == r {
+        (g - b) / chroma
+    } else if max == g {
+        (b - r) / chroma + 2.0
+    } else {
+        (r - g) / chroma + 4.0
+    };
+    (Some((hue * 60.0).rem_euclid(360.0)), lightness)
+}
+
+fn to_linear(value: u8) -> f64 {
+    let value = value as f64 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn lab_f(t: f64) -> f64 {
+    const DELTA: f64 = 6.0 / 29.0;
+    if t > DELTA * DELTA * DELTA {
+        t.cbrt()
+    } else {
+        t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+    }
+}
+
+/// Converts an sRGB colour to CIE L*a*b* under D65, with L* from 0.0 to
+/// 100.0 and a* and b* roughly within -128.0 to 128.0.
+pub fn get_lab(colour: Rgb<u8>) -> [f64; 3] {
+    let [r, g, b] = colour.data;
+    let (r, g, b) = (to_linear(r), to_linear(g), to_linear(b));
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let (fx, fy, fz) = (lab_f(x), lab_f(y), lab_f(z));
+
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+fn from_linear(value: f64) -> u8 {
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (value * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+fn lab_f_inverse(t: f64) -> f64 {
+    const DELTA: f64 = 6.0 / 29.0;
+    if t > DELTA {
+        t * t * t
+    } else {
+        3.0 * DELTA * DELTA * (t - 4.0 / 29.0)
+    }
+}
+
+/// The inverse of `get_lab`, clipping colours outside sRGB.
+pub fn get_rgb(lab: &[f64; 3]) -> Rgb<u8> {
+    let fy = (lab[0] + 16.0) / 116.0;
+    let fx = fy + lab[1] / 500.0;
+    let fz = fy - lab[2] / 200.0;
+    let x = lab_f_inverse(fx) * 0.95047;
+    let y = lab_f_inverse(fy);
+    let z = lab_f_inverse(fz) * 1.08883;
+
+    Rgb([
+        from_linear(3.2406 * x - 1.5372 * y - 0.4986 * z),
+        from_linear(-0.9689 * x + 1.8758 * y + 0.0415 * z),
+        from_linear(0.0557 * x - 0.2040 * y + 1.0570 * z),
+    ])
+}
+
+/// Euclidean distance between two L*a*b* colours, the CIE76 colour
+/// difference. Around 2.3 is just noticeable.
+pub fn get_delta_e(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Returns the mean colour of `img`.
+pub fn get_mean_colour(img: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Rgb<u8> {
+    let sums = get_channel_sums(img);
+    let samples = (img.width() * img.height()).max(1) as u64;
+    Rgb([
+        (sums[0] / samples) as u8,
+        (sums[1] / samples) as u8,
+        (sums[2] / samples) as u8,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lab_round_trip() {
+        for r in (0..=255).step_by(15) {
+            for g in (0..=255).step_by(15) {
+                for b in (0..=255).step_by(15) {
+                    let colour = Rgb([r as u8, g as u8, b as u8]);
+                    let back = get_rgb(&get_lab(colour));
+                    for (&x, &y) in colour.data.iter().zip(back.data.iter()) {
+                        let difference = (x as i32 - y as i32).abs();
+                        assert!(difference <= 1, "{:?} came back as {:?}", colour, back);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lab_of_black_and_white() {
+        let black = get_lab(Rgb([0, 0, 0]));
+        let white = get_lab(Rgb([255, 255, 255]));
+        assert!(black.iter().all(|value| value.abs() < 1e-6));
+        // The four place sRGB matrix leaves white a hair off neutral.
+        assert!((white[0] - 100.0).abs() < 0.1);
+        assert!(white[1].abs() < 0.1 && white[2].abs() < 0.1);
+    }
+
+    #[test]
+    fn out_of_gamut_is_clipped() {
+        assert_eq!(get_rgb(&[150.0, 0.0, 0.0]), Rgb([255, 255, 255]));
+        assert_eq!(get_rgb(&[-10.0, 0.0, 0.0]), Rgb([0, 0, 0]));
+    }
+}
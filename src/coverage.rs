@@ -0,0 +1,257 @@
+use image::{ImageBuffer, Rgb};
+use rayon::prelude::*;
+
+use crate::{
+    find_top_matches, get_cell, get_cell_thumbnail, get_delta_e, get_lab, get_mean_colour,
+    CancelToken, Library, MosaicConfig,
+};
+
+/// Bins along each of the a* and b* axes of `ColourHistogram::ab`.
+pub const AB_BINS: usize = 32;
+
+/// Bins along L* in `ColourHistogram::lightness`.
+pub const LIGHTNESS_BINS: usize = 20;
+
+/// Colour difference from the closest library photo beyond which a cell
+/// counts as poorly covered.
+pub const POOR_COVERAGE_DELTA_E: f64 = 15.0;
+
+/// How often colours fall in each region of L*a*b* space, as fractions of
+/// all the colours counted.
+#[derive(Clone, Debug)]
+pub struct ColourHistogram {
+    /// `AB_BINS` rows of b*, each of `AB_BINS` columns of a*, both axes
+    /// running from -128.0 to 128.0.
+    pub ab: Vec<f64>,
+    /// L* from 0.0 to 100.0.
+    pub lightness: Vec<f64>,
+}
+
+/// Returns which of `bins` equal bins from `min` to `max` `value` falls in,
+/// values outside going in the first or last.
+pub(crate) fn get_bin(value: f64, min: f64, max: f64, bins: usize) -> usize {
+    let bin = ((value - min) / (max - min) * bins as f64).floor();
+    (bin.max(0.0) as usize).min(bins - 1)
+}
+
+impl ColourHistogram {
+    pub fn new(colours: &[[f64; 3]]) -> Self {
+        let mut ab = vec![0.0; AB_BINS * AB_BINS];
+        let mut lightness = vec![0.0; LIGHTNESS_BINS];
+        let weight = 1.0 / colours.len().max(1) as f64;
+        for [l, a, b] in colours.iter() {
+            let column = get_bin(*a, -128.0, 128.0, AB_BINS);
+            let row = get_bin(*b, -128.0, 128.0, AB_BINS);
+            ab[row * AB_BINS + column] += weight;
+            lightness[get_bin(*l, 0.0, 100.0, LIGHTNESS_BINS)] += weight;
+        }
+
+        ColourHistogram { ab, lightness }
+    }
+}
+
+/// How well the library covers one cell of the target.
+#[derive(Clone, Debug)]
+pub struct CellCoverage {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Mean colour of the cell, in L*a*b*.
+    pub colour: [f64; 3],
+    /// Score of the cell's best match, see `find_top_matches`.
+    pub score: f64,
+    /// Colour difference between the cell and the library photo closest to
+    /// it in mean colour, see `get_delta_e`.
+    pub delta_e: f64,
+}
+
+impl CellCoverage {
+    pub fn is_poor(&self) -> bool {
+        self.delta_e > POOR_COVERAGE_DELTA_E
+    }
+}
+
+/// The colours of the library against those of the target's cells.
+#[derive(Clone, Debug)]
+pub struct CoverageReport {
+    /// Mean colours of the photos matching may use.
+    pub library: ColourHistogram,
+    /// Mean colours of the cells.
+    pub target: ColourHistogram,
+    pub cells: Vec<CellCoverage>,
+}
+
+impl CoverageReport {
+    /// Returns the `count` cells with the highest best match scores, worst
+    /// first.
+    pub fn get_worst_cells(&self, count: usize) -> Vec<&CellCoverage> {
+        let mut cells: Vec<&CellCoverage> = self.cells.iter().collect();
+        cells.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        cells.truncate(count);
+        cells
+    }
+
+    pub fn get_poor_count(&self) -> usize {
+        self.cells.iter().filter(|cell| cell.is_poor()).count()
+    }
+}
+
+/// Compares every one of `cells`, see `get_visible_cells`, with the library,
+/// both by best match score and by mean colour. Cells are worked on in
+/// parallel and `cancel` is checked before each one; if it has been
+/// cancelled `None` is returned.
+pub fn get_coverage(
+    input: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    library: &Library,
+    cells: &[(u32, u32, u32, u32)],
+    config: &MosaicConfig,
+    cancel: &CancelToken,
+) -> Option<CoverageReport> {
+    let library_colours: Vec<[f64; 3]> = library
+        .pics_data
+        .par_iter()
+        .filter(|pic_data| !library.is_excluded(&pic_data.path))
+        .map(|pic_data| get_lab(get_mean_colour(&pic_data.preview)))
+        .collect();
+
+    let cells: Vec<Option<CellCoverage>> = cells
+        .par_iter()
+        .map(|&(x, y, width, height)| {
+            if cancel.is_cancelled() {
+                return None;
+            }
+
+            let crop = get_cell(input, x, y, width, height);
+            let colour = get_lab(get_mean_colour(&crop));
+            let (aspect, thumbnail) = get_cell_thumbnail(&crop, library, config);
+            let best = find_top_matches(
+                aspect,
+                &thumbnail,
+                library,
+                &config.score,
+                config.transforms,
+                1,
+            );
+            let score = best
+                .first()
+                .map_or(f64::INFINITY, |candidate| candidate.score);
+            let delta_e = library_colours
+                .iter()
+                .map(|library_colour| get_delta_e(&colour, library_colour))
+                .fold(f64::INFINITY, f64::min);
+
+            Some(CellCoverage {
+                x,
+                y,
+                width,
+                height,
+                colour,
+                score,
+                delta_e,
+            })
+        })
+        .collect();
+    let cells: Vec<CellCoverage> = cells.into_iter().collect::<Option<_>>()?;
+    if cancel.is_cancelled() {
+        return None;
+    }
+
+    let cell_colours: Vec<[f64; 3]> = cells.iter().map(|cell| cell.colour).collect();
+    Some(CoverageReport {
+        library: ColourHistogram::new(&library_colours),
+        target: ColourHistogram::new(&cell_colours),
+        cells,
+    })
+}
+
+/// Side of each a*b* bin in `render_coverage_plot`, in pixels.
+const PLOT_BIN_SIZE: u32 = 8;
+
+/// Height of the lightness histograms in `render_coverage_plot`.
+const PLOT_BAR_HEIGHT: u32 = 80;
+
+/// Scales `value` against the largest value in its histogram to a channel
+/// level, brightening faint bins so they still show.
+fn get_level(value: f64, max: f64) -> u8 {
+    if max <= 0.0 {
+        0
+    } else {
+        ((value / max).sqrt() * 255.0) as u8
+    }
+}
+
+fn get_max(values: &[f64]) -> f64 {
+    values.iter().copied().fold(0.0, f64::max)
+}
+
+/// Draws the library's colours in green over the target's in magenta, a*
+/// across and b* down, with the lightness histograms of both beneath, dark
+/// to light. Colours the target needs but the library lacks show magenta.
+pub fn render_coverage_plot(report: &CoverageReport) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let side = AB_BINS as u32 * PLOT_BIN_SIZE;
+    let mut plot = ImageBuffer::from_pixel(side, side + PLOT_BAR_HEIGHT + 1, Rgb([24, 24, 24]));
+
+    let library_max = get_max(&report.library.ab);
+    let target_max = get_max(&report.target.ab);
+    for (x, y, pixel) in plot.enumerate_pixels_mut() {
+        if y >= side {
+            continue;
+        }
+        if x == side / 2 || y == side / 2 {
+            *pixel = Rgb([80, 80, 80]);
+        }
+        let bin = (y / PLOT_BIN_SIZE) as usize * AB_BINS + (x / PLOT_BIN_SIZE) as usize;
+        let library = get_level(report.library.ab[bin], library_max);
+        let target = get_level(report.target.ab[bin], target_max);
+        if library > 0 || target > 0 {
+            *pixel = Rgb([target, library, target]);
+        }
+    }
+
+    let library_max = get_max(&report.library.lightness);
+    let target_max = get_max(&report.target.lightness);
+    let bar_width = side / LIGHTNESS_BINS as u32;
+    for bin in 0..LIGHTNESS_BINS {
+        let bars = [
+            (
+                report.library.lightness[bin] / library_max.max(f64::MIN_POSITIVE),
+                Rgb([0, 255, 0]),
+            ),
+            (
+                report.target.lightness[bin] / target_max.max(f64::MIN_POSITIVE),
+                Rgb([255, 0, 255]),
+            ),
+        ];
+        for (i, (fraction, colour)) in bars.iter().enumerate() {
+            let height = (fraction * PLOT_BAR_HEIGHT as f64) as u32;
+            let x_start = bin as u32 * bar_width + i as u32 * bar_width / 2;
+            for x in x_start..x_start + bar_width / 2 {
+                for y in 0..height {
+                    plot.put_pixel(x, side + PLOT_BAR_HEIGHT - y, *colour);
+                }
+            }
+        }
+    }
+
+    plot
+}
+
+/// Tints the poorly covered cells of `input` red, see `CellCoverage::is_poor`.
+pub fn render_coverage_overlay(
+    input: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    report: &CoverageReport,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let mut output = input.clone();
+    for cell in report.cells.iter().filter(|cell| cell.is_poor()) {
+        for y in cell.y..cell.y + cell.height {
+            for x in cell.x..cell.x + cell.width {
+                let pixel = output.get_pixel_mut(x, y);
+                let [r, g, b] = pixel.data;
+                pixel.data = [(r / 2).saturating_add(128), g / 2, b / 2];
+            }
+        }
+    }
+
+    output
+}
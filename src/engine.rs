@@ -0,0 +1,801 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use image::imageops::{replace, resize};
+use image::{DynamicImage, GenericImage, ImageBuffer, Rgb};
+use rand::distributions::{Distribution, Uniform};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+
+use crate::{
+    add_alpha, fade_out, fill_tile, find_best_match, find_best_match_cascade,
+    find_top_matches_where, flatten, get_candidate, get_cell_tile, get_channel_sums,
+    get_mosaic_alpha, get_opacity, open_image, render_grout, Alpha, Candidate, CascadeConfig,
+    GroutConfig, Library, MatchData, ProgressCounter, ProgressSink, ScoreConfig, Transform,
+    BACKGROUND,
+};
+
+/// How the target is cut into cells.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Layout {
+    /// Columns and rows of random widths between the smallest and largest
+    /// tile size, see `get_rulers`.
+    #[default]
+    Random,
+    /// Equal columns and rows halfway between the smallest and largest size.
+    Grid,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 2] = [Layout::Random, Layout::Grid];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Random => "random",
+            Layout::Grid => "grid",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL
+            .iter()
+            .copied()
+            .find(|layout| layout.name() == name)
+    }
+}
+
+/// Everything that decides which photo goes in which cell.
+#[derive(Clone, Debug)]
+pub struct MosaicConfig {
+    pub score: ScoreConfig,
+    /// Also consider mirrored and rotated photos, see `Transform`.
+    pub transforms: bool,
+    /// Match with `find_best_match_cascade` instead of `find_best_match`.
+    /// Ignored when `reuse_limit` is set.
+    pub cascade: Option<CascadeConfig>,
+    /// Smallest and largest tile side, in output pixels.
+    pub min_tile: u32,
+    pub max_tile: u32,
+    pub layout: Layout,
+    /// Most cells any one photo may fill, or `None` for no limit, as is
+    /// `Some(0)`. Once every photo is used up, each may fill that many cells
+    /// again.
+    pub reuse_limit: Option<usize>,
+    /// How far each tile's average colour is moved towards its cell's, from
+    /// 0.0 for untouched photos to 1.0 for an exact match.
+    pub colour_correction: f64,
+    /// Size of the mosaic relative to the target.
+    pub output_scale: f64,
+    /// Seed for the layout, or `None` for a different one every time.
+    pub seed: Option<u64>,
+    /// Fraction of a cell the target's mask must cover for the cell to get a
+    /// tile, see `get_visible_cells`. At 0.0 any cell it touches does, and at
+    /// 1.0 only the cells it wholly covers.
+    pub mask_threshold: f64,
+    /// What fills the mosaic where there are no tiles.
+    pub background: Rgb<u8>,
+    /// Save the mosaic with an alpha channel, transparent where there are no
+    /// tiles and where the target is, instead of over `background`.
+    pub rgba_output: bool,
+    /// Lines to leave between the tiles, or `None` to place them edge to
+    /// edge.
+    pub grout: Option<GroutConfig>,
+}
+
+impl Default for MosaicConfig {
+    fn default() -> Self {
+        MosaicConfig {
+            score: ScoreConfig::default(),
+            transforms: false,
+            cascade: None,
+            min_tile: 120,
+            max_tile: 320,
+            layout: Layout::default(),
+            reuse_limit: None,
+            colour_correction: 0.0,
+            output_scale: 1.0,
+            seed: None,
+            mask_threshold: 0.0,
+            background: BACKGROUND,
+            rgba_output: false,
+            grout: None,
+        }
+    }
+}
+
+/// A flag shared between the thread generating a mosaic and whoever may want
+/// to stop it. Clones refer to the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Splits `length` pixels into steps drawn from `min..max`, returning the
+/// offsets between them starting with 0 and ending with `length`. What is
+/// left over once no further step fits is spread over the earlier steps.
+pub fn get_rulers<R: Rng>(length: u32, min: u32, max: u32, rng: &mut R) -> Vec<u32> {
+    let mut rulers = Vec::new();
+
+    let mut pixels = 0;
+    let distribution = Uniform::new(min, max);
+    loop {
+        let step = distribution.sample(rng);
+
+        if pixels + step > length {
+            break;
+        }
+
+        pixels += step;
+        rulers.push(pixels);
+    }
+
+    if rulers.is_empty() {
+        // Not even one step fits, so the whole length is a single step.
+        rulers.push(length);
+        pixels = length;
+    }
+
+    let mut remaining = length - pixels;
+    while remaining > 0 {
+        for i in 0..rulers.len() {
+            for ruler in rulers[i..].iter_mut() {
+                *ruler += 1;
+            }
+
+            remaining -= 1;
+            if remaining == 0 {
+                break;
+            }
+        }
+    }
+
+    rulers.insert(0, 0);
+    rulers
+}
+
+/// Splits `length` pixels into equal steps as close to `size` as possible.
+pub fn get_grid_rulers(length: u32, size: u32) -> Vec<u32> {
+    let steps = ((length as f64 / size.max(1) as f64).round() as u32).max(1);
+    (0..=steps)
+        .map(|i| (i as u64 * length as u64 / steps as u64) as u32)
+        .collect()
+}
+
+/// Returns the column and row rulers for a `width` x `height` mosaic.
+pub fn get_layout(width: u32, height: u32, config: &MosaicConfig) -> (Vec<u32>, Vec<u32>) {
+    let min = config.min_tile.max(1);
+    let max = config.max_tile.max(min + 1);
+    match config.layout {
+        Layout::Random => {
+            let mut rng = match config.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            };
+            let x_rulers = get_rulers(width, min, max, &mut rng);
+            let y_rulers = get_rulers(height, min, max, &mut rng);
+            (x_rulers, y_rulers)
+        }
+        Layout::Grid => {
+            let size = (min + max) / 2;
+            (get_grid_rulers(width, size), get_grid_rulers(height, size))
+        }
+    }
+}
+
+/// Returns the `x`, `y`, width and height of every cell of the grid given
+/// by `x_rulers` and `y_rulers`.
+pub fn get_cells(x_rulers: &[u32], y_rulers: &[u32]) -> Vec<(u32, u32, u32, u32)> {
+    let mut cells = Vec::new();
+    for x in x_rulers.windows(2) {
+        for y in y_rulers.windows(2) {
+            cells.push((x[0], y[0], x[1] - x[0], y[1] - y[0]));
+        }
+    }
+
+    cells
+}
+
+/// Returns the cells of the grid given by `x_rulers` and `y_rulers` that
+/// `mask`, see `Target::get_mask`, covers at least `threshold` of, or any of
+/// at 0.0. Only these get tiles.
+pub fn get_visible_cells(
+    x_rulers: &[u32],
+    y_rulers: &[u32],
+    mask: Option<&Alpha>,
+    threshold: f64,
+) -> Vec<(u32, u32, u32, u32)> {
+    let mut cells = get_cells(x_rulers, y_rulers);
+    if let Some(mask) = mask {
+        cells.retain(|&(x, y, width, height)| {
+            let opacity = get_opacity(mask, x, y, width, height);
+            if threshold > 0.0 {
+                opacity >= threshold
+            } else {
+                opacity > 0.0
+            }
+        });
+    }
+
+    cells
+}
+
+/// Resizes the target to the size of the mosaic made from it.
+pub fn get_scaled_input(
+    input: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    scale: f64,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    if scale == 1.0 {
+        return input.clone();
+    }
+
+    let width = ((input.width() as f64 * scale).round() as u32).max(1);
+    let height = ((input.height() as f64 * scale).round() as u32).max(1);
+    resize(input, width, height, image::FilterType::CatmullRom)
+}
+
+/// Copies the `width` x `height` region at `x`, `y` out of `img`.
+pub fn get_cell(
+    img: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    ImageBuffer::from_fn(width, height, |i, j| *img.get_pixel(x + i, y + j))
+}
+
+/// Returns the aspect and thumbnail `cell` is matched on. With grout those of
+/// only the part of it the photo is fitted into, see
+/// `GroutConfig::get_photo_rect`, so photos are scored as they will be shown.
+pub fn get_cell_thumbnail(
+    cell: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    library: &Library,
+    config: &MosaicConfig,
+) -> (f64, ImageBuffer<Rgb<u8>, Vec<u8>>) {
+    let (width, height) = cell.dimensions();
+    let photo = match &config.grout {
+        Some(grout) => {
+            let (x, y, photo_width, photo_height) = grout.get_photo_rect(width, height);
+            get_cell(cell, x, y, photo_width, photo_height)
+        }
+        None => cell.clone(),
+    };
+    let aspect = photo.width() as f64 / photo.height() as f64;
+    (aspect, library.get_thumbnail(&photo))
+}
+
+/// Loads a library photo and renders it as the tile for a `width` x `height`
+/// cell.
+pub fn get_tile(
+    path: &Path,
+    transform: Transform,
+    width: u32,
+    height: u32,
+) -> image::ImageResult<ImageBuffer<Rgb<u8>, Vec<u8>>> {
+    let img = flatten(&open_image(path)?, BACKGROUND);
+    Ok(fill_tile(&transform.apply(&img), width, height))
+}
+
+/// Moves the average colour of `tile` towards that of `cell` by `strength`,
+/// where 1.0 makes them equal.
+pub fn correct_colour(
+    tile: &mut ImageBuffer<Rgb<u8>, Vec<u8>>,
+    cell: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    strength: f64,
+) {
+    let tile_sums = get_channel_sums(tile);
+    let cell_sums = get_channel_sums(cell);
+    let tile_samples = (tile.width() * tile.height()).max(1) as f64;
+    let cell_samples = (cell.width() * cell.height()).max(1) as f64;
+
+    let mut shift = [0.0; 3];
+    for (c, shift) in shift.iter_mut().enumerate() {
+        let difference = cell_sums[c] as f64 / cell_samples - tile_sums[c] as f64 / tile_samples;
+        *shift = difference * strength;
+    }
+    for pixel in tile.pixels_mut() {
+        for (value, shift) in pixel.data.iter_mut().zip(shift.iter()) {
+            *value = (*value as f64 + shift).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Chooses a cell for each of the library's pinned photos, best scoring pairs
+/// first, so that no two pins share a cell. Pins that don't fit are dropped.
+fn get_pinned_cells(
+    input: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    library: &Library,
+    cells: &[(u32, u32, u32, u32)],
+    config: &MosaicConfig,
+) -> Vec<Option<Candidate>> {
+    let mut pinned_cells = vec![None; cells.len()];
+    let pins: Vec<_> = library
+        .pics_data
+        .iter()
+        .filter(|pic_data| library.is_pinned(&pic_data.path))
+        .collect();
+    if pins.is_empty() {
+        return pinned_cells;
+    }
+
+    let thumbnails: Vec<_> = cells
+        .par_iter()
+        .map(|&(x, y, width, height)| {
+            get_cell_thumbnail(&get_cell(input, x, y, width, height), library, config)
+        })
+        .collect();
+    let mut pairs: Vec<(usize, usize, Candidate)> = pins
+        .par_iter()
+        .enumerate()
+        .flat_map(|(i, pic_data)| {
+            thumbnails
+                .par_iter()
+                .enumerate()
+                .map(move |(j, (aspect, thumbnail))| {
+                    let candidate = get_candidate(
+                        *aspect,
+                        thumbnail,
+                        pic_data,
+                        &config.score,
+                        config.transforms,
+                    );
+                    (i, j, candidate)
+                })
+        })
+        .collect();
+    pairs.sort_by(|a, b| a.2.score.partial_cmp(&b.2.score).unwrap());
+
+    let mut placed = vec![false; pins.len()];
+    for (i, j, candidate) in pairs {
+        if !placed[i] && pinned_cells[j].is_none() {
+            placed[i] = true;
+            pinned_cells[j] = Some(candidate);
+        }
+    }
+
+    pinned_cells
+}
+
+/// Number of candidates kept per cell each round of `get_limited_cells`.
+const LIMITED_CANDIDATES: usize = 8;
+
+/// Chooses a photo for every cell not already in `chosen` without any photo
+/// filling more than `limit` cells until all of them have. Best scoring pairs
+/// across the whole mosaic are placed first; cells whose candidates all run
+/// out are matched again against the photos still available. Returns `None`
+/// if cancelled.
+fn get_limited_cells(
+    input: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    library: &Library,
+    cells: &[(u32, u32, u32, u32)],
+    mut chosen: Vec<Option<Candidate>>,
+    config: &MosaicConfig,
+    limit: usize,
+    cancel: &CancelToken,
+) -> Option<Vec<Option<Candidate>>> {
+    let thumbnails: Vec<_> = cells
+        .par_iter()
+        .map(|&(x, y, width, height)| {
+            get_cell_thumbnail(&get_cell(input, x, y, width, height), library, config)
+        })
+        .collect();
+
+    let mut uses: HashMap<PathBuf, usize> = HashMap::new();
+    for candidate in chosen.iter().flatten() {
+        *uses.entry(candidate.path.clone()).or_default() += 1;
+    }
+
+    loop {
+        let open: Vec<usize> = (0..cells.len()).filter(|&i| chosen[i].is_none()).collect();
+        if open.is_empty() {
+            return Some(chosen);
+        }
+
+        let is_available = |path: &Path| uses.get(path).map_or(true, |&used| used < limit);
+        let any_available = library
+            .pics_data
+            .iter()
+            .any(|pic_data| !library.is_excluded(&pic_data.path) && is_available(&pic_data.path));
+        if !any_available {
+            if uses.is_empty() {
+                // No photo can be used at all, leave the rest to the caller.
+                return Some(chosen);
+            }
+            // Every photo is used up, so each may fill `limit` more cells.
+            uses.clear();
+            continue;
+        }
+
+        let matches: Vec<Vec<Candidate>> = open
+            .par_iter()
+            .map(|&i| {
+                if cancel.is_cancelled() {
+                    return Vec::new();
+                }
+
+                let (aspect, thumbnail) = &thumbnails[i];
+                find_top_matches_where(
+                    *aspect,
+                    thumbnail,
+                    library,
+                    &config.score,
+                    config.transforms,
+                    LIMITED_CANDIDATES,
+                    &|pic_data| is_available(&pic_data.path),
+                )
+            })
+            .collect();
+        if cancel.is_cancelled() {
+            return None;
+        }
+
+        let mut pairs: Vec<(usize, Candidate)> = open
+            .into_iter()
+            .zip(matches)
+            .flat_map(|(i, candidates)| candidates.into_iter().map(move |candidate| (i, candidate)))
+            .collect();
+        pairs.sort_by(|a, b| {
+            a.1.score
+                .partial_cmp(&b.1.score)
+                .unwrap()
+                .then(a.0.cmp(&b.0))
+        });
+
+        for (i, candidate) in pairs {
+            let used = uses.entry(candidate.path.clone()).or_default();
+            if chosen[i].is_none() && *used < limit {
+                *used += 1;
+                chosen[i] = Some(candidate);
+            }
+        }
+    }
+}
+
+/// Why `get_match_data` made no mosaic.
+#[derive(Debug)]
+pub enum MatchError {
+    Cancelled,
+    /// The library is empty or every photo in it is excluded.
+    NoPictures,
+    /// The photo chosen for a cell couldn't be opened.
+    Tile(PathBuf, image::ImageError),
+}
+
+impl fmt::Display for MatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatchError::Cancelled => write!(f, "Cancelled"),
+            MatchError::NoPictures => write!(f, "There are no pictures to use, all are excluded"),
+            MatchError::Tile(path, err) => write!(f, "Couldn't open {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl Error for MatchError {}
+
+/// Matches every one of `cells`, see `get_visible_cells`, against the
+/// library and renders its tile, reporting each cell to `progress`.
+/// Pinned photos are placed first and excluded ones are never used. Tiles
+/// are colour corrected towards their cells as `config` asks.
+/// Cells are worked on in parallel and `cancel` is checked before each one;
+/// matching stops at the first cell that fails or once it is cancelled.
+pub fn get_match_data(
+    input: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    library: &Library,
+    cells: &[(u32, u32, u32, u32)],
+    config: &MosaicConfig,
+    cancel: &CancelToken,
+    progress: &dyn ProgressSink,
+) -> Result<Vec<MatchData>, MatchError> {
+    if library.usable_count() == 0 {
+        return Err(MatchError::NoPictures);
+    }
+
+    progress.layout_done(cells.len());
+    let counter = ProgressCounter::new(cells.len());
+    let mut chosen = get_pinned_cells(input, library, cells, config);
+    if let Some(limit) = config.reuse_limit.filter(|&limit| limit > 0) {
+        chosen = get_limited_cells(input, library, cells, chosen, config, limit, cancel)
+            .ok_or(MatchError::Cancelled)?;
+    }
+
+    let match_data: Result<Vec<MatchData>, MatchError> = cells
+        .par_iter()
+        .copied()
+        .zip(chosen)
+        .map(|((x, y, width, height), chosen)| {
+            if cancel.is_cancelled() {
+                return Err(MatchError::Cancelled);
+            }
+
+            let crop = get_cell(input, x, y, width, height);
+            let (aspect, thumbnail) = get_cell_thumbnail(&crop, library, config);
+
+            let best_match = match (chosen, &config.cascade) {
+                (Some(chosen), _) => Some((chosen.path, chosen.transform)),
+                (None, Some(cascade)) => find_best_match_cascade(
+                    aspect,
+                    &thumbnail,
+                    library,
+                    &config.score,
+                    config.transforms,
+                    cascade,
+                ),
+                (None, None) => find_best_match(
+                    aspect,
+                    &thumbnail,
+                    library,
+                    &config.score,
+                    config.transforms,
+                ),
+            };
+            let (best_match, transform) = best_match.ok_or(MatchError::NoPictures)?;
+            let tile = get_cell_tile(&best_match, transform, &crop, config)
+                .map_err(|err| MatchError::Tile(best_match.clone(), err))?;
+
+            let match_data = MatchData {
+                x,
+                y,
+                path: best_match,
+                transform,
+                tile,
+            };
+            progress.cell_matched(&match_data, counter.step());
+            Ok(match_data)
+        })
+        .collect();
+
+    if cancel.is_cancelled() {
+        return Err(MatchError::Cancelled);
+    }
+
+    match_data
+}
+
+fn is_png(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("png"))
+}
+
+/// Where a mosaic asked to be saved at `path` should go: `path` itself, or
+/// with a `.png` extension if `config.rgba_output` is set, as the other
+/// formats drop the alpha channel.
+pub fn get_output_path(path: &Path, config: &MosaicConfig) -> PathBuf {
+    if config.rgba_output && !is_png(path) {
+        path.with_extension("png")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Finishes a mosaic rendered by `render` for showing and saving. Where
+/// there are no tiles or the target's `alpha` is transparent, the mosaic is
+/// left transparent if `config.rgba_output` is set and faded out to
+/// `config.background` otherwise.
+pub fn finish_mosaic(
+    output: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    match_data: &[MatchData],
+    alpha: Option<&Alpha>,
+    config: &MosaicConfig,
+) -> DynamicImage {
+    let (width, height) = output.dimensions();
+    let alpha = get_mosaic_alpha(width, height, match_data, alpha);
+    match (alpha, config.rgba_output) {
+        (alpha, true) => DynamicImage::ImageRgba8(add_alpha(output, alpha.as_ref())),
+        (Some(alpha), false) => {
+            DynamicImage::ImageRgb8(fade_out(output, &alpha, config.background))
+        }
+        (None, false) => DynamicImage::ImageRgb8(output.clone()),
+    }
+}
+
+/// Saves a mosaic finished by `finish_mosaic` and reports it to `progress`.
+/// Transparent mosaics can only be saved as PNG, see `get_output_path`.
+pub fn save_mosaic(
+    mosaic: &DynamicImage,
+    path: &Path,
+    progress: &dyn ProgressSink,
+) -> io::Result<()> {
+    let colour = mosaic.color();
+    if colour == image::ColorType::RGBA(8) && !is_png(path) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "transparent mosaics can only be saved as PNG",
+        ));
+    }
+    let (width, height) = mosaic.dimensions();
+    image::save_buffer(path, &mosaic.raw_pixels(), width, height, colour)?;
+    progress.render_written(path);
+
+    Ok(())
+}
+
+/// Counts the cells each library photo fills.
+pub fn get_usage_counts(match_data: &[MatchData]) -> HashMap<PathBuf, usize> {
+    let mut counts = HashMap::new();
+    for m in match_data.iter() {
+        *counts.entry(m.path.clone()).or_default() += 1;
+    }
+
+    counts
+}
+
+/// Places every tile over a copy of `input`, set in `grout` if there is
+/// any, see `render_grout`.
+pub fn render(
+    input: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    match_data: &[MatchData],
+    grout: Option<&GroutConfig>,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let mut output = input.clone();
+    match grout {
+        Some(grout) => render_grout(&mut output, match_data, grout),
+        None => {
+            for m in match_data.iter() {
+                replace(&mut output, &m.tile, m.x, m.y);
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_library, NoProgress};
+    use image::Luma;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    const RED: Rgb<u8> = Rgb { data: [255, 0, 0] };
+    const BLUE: Rgb<u8> = Rgb { data: [0, 0, 255] };
+
+    /// Saves a plain photo of each of `colours` in a new folder named after
+    /// the test and indexes it.
+    fn get_test_library(name: &str, colours: &[Rgb<u8>]) -> (PathBuf, Library) {
+        let dir = env::temp_dir().join(format!("mosaic-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (i, &colour) in colours.iter().enumerate() {
+            let img = ImageBuffer::from_pixel(16, 12, colour);
+            img.save(dir.join(format!("{}.png", i))).unwrap();
+        }
+        let library = get_library(&dir, 8, &NoProgress).unwrap();
+        (dir, library)
+    }
+
+    #[test]
+    fn finished_mosaic_is_empty_outside_the_tiles() {
+        let output = ImageBuffer::from_pixel(4, 2, RED);
+        let match_data = [MatchData {
+            x: 0,
+            y: 0,
+            path: PathBuf::from("0.png"),
+            transform: Transform::Identity,
+            tile: ImageBuffer::from_pixel(2, 2, RED),
+        }];
+        let config = MosaicConfig {
+            background: BLUE,
+            ..MosaicConfig::default()
+        };
+
+        let finished = finish_mosaic(&output, &match_data, None, &config).to_rgb();
+        assert_eq!(*finished.get_pixel(1, 1), RED);
+        assert_eq!(*finished.get_pixel(2, 1), BLUE);
+
+        let config = MosaicConfig {
+            rgba_output: true,
+            ..config
+        };
+        let finished = finish_mosaic(&output, &match_data, None, &config).to_rgba();
+        assert_eq!(finished.get_pixel(1, 1).data[3], 255);
+        assert_eq!(finished.get_pixel(2, 1).data[3], 0);
+    }
+
+    #[test]
+    fn zero_reuse_limit_is_no_limit() {
+        let (dir, library) = get_test_library("reuse", &[RED, BLUE]);
+        let input = ImageBuffer::from_fn(32, 32, |x, _| if x < 16 { RED } else { BLUE });
+        let cells = get_cells(&[0, 16, 32], &[0, 16, 32]);
+        let config = MosaicConfig {
+            reuse_limit: Some(0),
+            ..MosaicConfig::default()
+        };
+
+        let match_data = get_match_data(
+            &input,
+            &library,
+            &cells,
+            &config,
+            &CancelToken::new(),
+            &NoProgress,
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        let match_data = match_data.unwrap();
+        assert_eq!(match_data.len(), cells.len());
+        for m in match_data.iter() {
+            let expected = if m.x < 16 { "0.png" } else { "1.png" };
+            assert!(m.path.ends_with(expected), "{:?} at {}", m.path, m.x);
+        }
+    }
+
+    #[test]
+    fn grout_matches_only_the_photo_rect() {
+        let (dir, library) = get_test_library("grout-match", &[RED, BLUE]);
+        // Mostly blue, but the photo only shows the red middle of each cell.
+        let input = ImageBuffer::from_fn(16, 16, |x, y| {
+            if (4..12).contains(&x) && (4..12).contains(&y) {
+                RED
+            } else {
+                BLUE
+            }
+        });
+        let cells = get_cells(&[0, 16], &[0, 16]);
+        let config = MosaicConfig {
+            grout: Some(GroutConfig {
+                width: 8,
+                ..GroutConfig::default()
+            }),
+            ..MosaicConfig::default()
+        };
+
+        let (aspect, _) = get_cell_thumbnail(&get_cell(&input, 0, 0, 16, 10), &library, &config);
+        assert_eq!(aspect, 8.0 / 2.0);
+
+        let match_data = get_match_data(
+            &input,
+            &library,
+            &cells,
+            &config,
+            &CancelToken::new(),
+            &NoProgress,
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        let match_data = match_data.unwrap();
+        assert!(
+            match_data[0].path.ends_with("0.png"),
+            "{:?}",
+            match_data[0].path
+        );
+    }
+
+    #[test]
+    fn mask_threshold_is_inclusive() {
+        // The left cells are wholly covered, the top right one half and the
+        // bottom right one not at all.
+        let mask = ImageBuffer::from_fn(4, 4, |x, y| {
+            if x < 2 || y < 1 {
+                Luma { data: [255] }
+            } else {
+                Luma { data: [0] }
+            }
+        });
+        let count =
+            |threshold| get_visible_cells(&[0, 2, 4], &[0, 2, 4], Some(&mask), threshold).len();
+        assert_eq!(count(0.0), 3);
+        assert_eq!(count(0.5), 3);
+        assert_eq!(count(0.6), 2);
+        assert_eq!(count(1.0), 2);
+    }
+}
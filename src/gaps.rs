@@ -0,0 +1,140 @@
+use image::{ImageBuffer, Rgb};
+
+use crate::{get_bin, get_rgb, CoverageReport};
+
+/// Fraction of cells with a better best match than the default
+/// under-served threshold, see `CoverageReport::get_score_quantile`.
+pub const DEFAULT_GAP_QUANTILE: f64 = 0.75;
+
+/// Bins along L* that under-served cells are grouped by.
+const GAP_LIGHTNESS_BINS: usize = 5;
+
+/// Bins along each of a* and b* that under-served cells are grouped by.
+const GAP_AB_BINS: usize = 8;
+
+/// A colour the library lacks: the mean colour of under-served cells close
+/// to one another in L*a*b*.
+#[derive(Clone, Debug)]
+pub struct WantedSwatch {
+    pub colour: Rgb<u8>,
+    pub lab: [f64; 3],
+    /// Under-served cells of this colour.
+    pub cells: usize,
+    /// Mean best match score of those cells.
+    pub mean_score: f64,
+}
+
+/// Which colours and brightnesses of a target the library serves poorly,
+/// most wanted first.
+#[derive(Clone, Debug)]
+pub struct GapReport {
+    /// Best match score above which a cell counts as under-served.
+    pub threshold: f64,
+    /// Under-served cells, out of `total_cells`.
+    pub under_served: usize,
+    pub total_cells: usize,
+    pub swatches: Vec<WantedSwatch>,
+}
+
+impl CoverageReport {
+    /// Returns the best match score that `fraction` of the cells are at or
+    /// below.
+    pub fn get_score_quantile(&self, fraction: f64) -> f64 {
+        let mut scores: Vec<f64> = self.cells.iter().map(|cell| cell.score).collect();
+        if scores.is_empty() {
+            return 0.0;
+        }
+        scores.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let index = (fraction * (scores.len() - 1) as f64).round() as usize;
+        scores[index.min(scores.len() - 1)]
+    }
+}
+
+fn get_gap_bin(lab: &[f64; 3]) -> usize {
+    let l = get_bin(lab[0], 0.0, 100.0, GAP_LIGHTNESS_BINS);
+    let a = get_bin(lab[1], -128.0, 128.0, GAP_AB_BINS);
+    let b = get_bin(lab[2], -128.0, 128.0, GAP_AB_BINS);
+    (l * GAP_AB_BINS + a) * GAP_AB_BINS + b
+}
+
+/// Groups the cells of `report` whose best match scores above `threshold`
+/// by colour and brightness, returning a swatch for each group ordered by
+/// how many cells it would help, then by how badly.
+pub fn get_gap_report(report: &CoverageReport, threshold: f64) -> GapReport {
+    let mut bins = vec![(0, [0.0; 3], 0.0); GAP_LIGHTNESS_BINS * GAP_AB_BINS * GAP_AB_BINS];
+    let mut under_served = 0;
+    for cell in report.cells.iter().filter(|cell| cell.score > threshold) {
+        let (count, lab_sum, score_sum) = &mut bins[get_gap_bin(&cell.colour)];
+        *count += 1;
+        for (sum, value) in lab_sum.iter_mut().zip(cell.colour.iter()) {
+            *sum += value;
+        }
+        // A cell nothing matches at all would swamp the mean.
+        *score_sum += if cell.score.is_finite() {
+            cell.score
+        } else {
+            threshold
+        };
+        under_served += 1;
+    }
+
+    let mut swatches: Vec<WantedSwatch> = bins
+        .into_iter()
+        .filter(|(count, _, _)| *count > 0)
+        .map(|(count, lab_sum, score_sum)| {
+            let lab = [
+                lab_sum[0] / count as f64,
+                lab_sum[1] / count as f64,
+                lab_sum[2] / count as f64,
+            ];
+            WantedSwatch {
+                colour: get_rgb(&lab),
+                lab,
+                cells: count,
+                mean_score: score_sum / count as f64,
+            }
+        })
+        .collect();
+    swatches.sort_by(|a, b| {
+        b.cells
+            .cmp(&a.cells)
+            .then(b.mean_score.partial_cmp(&a.mean_score).unwrap())
+    });
+
+    GapReport {
+        threshold,
+        under_served,
+        total_cells: report.cells.len(),
+        swatches,
+    }
+}
+
+/// Writes `report` as text, one swatch per line with its sRGB hex code,
+/// L*a*b* values, cell count and mean best match score.
+pub fn format_gap_report(report: &GapReport) -> String {
+    let mut text = format!(
+        "{} of {} cells under-served, scoring above {:.3}\n",
+        report.under_served, report.total_cells, report.threshold
+    );
+    for swatch in report.swatches.iter() {
+        let [r, g, b] = swatch.colour.data;
+        text += &format!(
+            "#{:02x}{:02x}{:02x}  L* {:5.1}  a* {:6.1}  b* {:6.1}  {} cells, mean score {:.3}\n",
+            r, g, b, swatch.lab[0], swatch.lab[1], swatch.lab[2], swatch.cells, swatch.mean_score
+        );
+    }
+
+    text
+}
+
+/// Draws `swatches` side by side as `size` x `size` squares, most wanted on
+/// the left.
+pub fn render_swatches(swatches: &[WantedSwatch], size: u32) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let width = size * swatches.len().max(1) as u32;
+    ImageBuffer::from_fn(width, size, |x, _| {
+        match swatches.get((x / size) as usize) {
+            Some(swatch) => swatch.colour,
+            None => Rgb([24, 24, 24]),
+        }
+    })
+}
@@ -0,0 +1,195 @@
+use std::path::Path;
+
+use image::imageops::replace;
+use image::{ImageBuffer, Rgb};
+
+use crate::{correct_colour, get_tile, MatchData, MosaicConfig, Transform};
+
+/// How dark a drop shadow is where it is darkest, from 0.0 to 1.0.
+const SHADOW_OPACITY: f64 = 0.5;
+
+/// Lines left between tiles, like the grout between real ones, and how the
+/// tiles sit in them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroutConfig {
+    /// Pixels between neighbouring tiles. Half as much is left round the
+    /// edge of the mosaic.
+    pub width: u32,
+    pub colour: Rgb<u8>,
+    /// Radius of the tiles' corners, or 0 for square ones.
+    pub corner_radius: u32,
+    /// How far each tile's shadow falls below and to the right of it, or 0
+    /// for none. The shadow's edge is as soft as it is long.
+    pub shadow: u32,
+}
+
+impl Default for GroutConfig {
+    fn default() -> Self {
+        GroutConfig {
+            width: 4,
+            colour: Rgb { data: [64, 64, 64] },
+            corner_radius: 0,
+            shadow: 0,
+        }
+    }
+}
+
+impl GroutConfig {
+    /// Returns the part of a `width` x `height` cell its photo is fitted
+    /// into, as its offset into the cell and its size. The rest is grout.
+    pub fn get_photo_rect(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let offset_x = (self.width / 2).min(width.saturating_sub(1) / 2);
+        let offset_y = (self.width / 2).min(height.saturating_sub(1) / 2);
+        let photo_width = width.saturating_sub(self.width).max(1);
+        let photo_height = height.saturating_sub(self.width).max(1);
+        (offset_x, offset_y, photo_width, photo_height)
+    }
+}
+
+/// Renders the tile for a cell of the target, `cell` being that part of it:
+/// the photo at `path` fitted inside the grout `config` asks for, if any, and
+/// colour corrected towards the cell. The tile is the size of the cell, with
+/// the grout left in its colour.
+pub fn get_cell_tile(
+    path: &Path,
+    transform: Transform,
+    cell: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    config: &MosaicConfig,
+) -> image::ImageResult<ImageBuffer<Rgb<u8>, Vec<u8>>> {
+    let (width, height) = cell.dimensions();
+    let grout = match &config.grout {
+        Some(grout) => grout,
+        None => {
+            let mut tile = get_tile(path, transform, width, height)?;
+            if config.colour_correction > 0.0 {
+                correct_colour(&mut tile, cell, config.colour_correction);
+            }
+            return Ok(tile);
+        }
+    };
+
+    let (x, y, photo_width, photo_height) = grout.get_photo_rect(width, height);
+    let mut photo = get_tile(path, transform, photo_width, photo_height)?;
+    if config.colour_correction > 0.0 {
+        correct_colour(&mut photo, cell, config.colour_correction);
+    }
+    let mut tile = ImageBuffer::from_pixel(width, height, grout.colour);
+    replace(&mut tile, &photo, x, y);
+
+    Ok(tile)
+}
+
+/// Signed distance from the centre of pixel `x`, `y` to the edge of a
+/// rectangle at `left`, `top` with corners of `radius`, negative inside it.
+fn get_distance(x: u32, y: u32, left: f64, top: f64, width: f64, height: f64, radius: f64) -> f64 {
+    let half_width = width / 2.0;
+    let half_height = height / 2.0;
+    let radius = radius.min(half_width).min(half_height);
+    let dx = (x as f64 + 0.5 - (left + half_width)).abs() - (half_width - radius);
+    let dy = (y as f64 + 0.5 - (top + half_height)).abs() - (half_height - radius);
+    dx.max(0.0).hypot(dy.max(0.0)) + dx.max(dy).min(0.0) - radius
+}
+
+fn mix(pixel: &mut Rgb<u8>, colour: Rgb<u8>, amount: f64) {
+    for (value, &colour) in pixel.data.iter_mut().zip(colour.data.iter()) {
+        *value = (*value as f64 * (1.0 - amount) + colour as f64 * amount).round() as u8;
+    }
+}
+
+/// Places every tile over `output` set in `grout`: each cell is filled with
+/// grout, then the tiles' shadows are cast over it, then the photos are laid
+/// on top with their corners rounded. Tiles are those of `get_cell_tile`.
+pub fn render_grout(
+    output: &mut ImageBuffer<Rgb<u8>, Vec<u8>>,
+    match_data: &[MatchData],
+    grout: &GroutConfig,
+) {
+    for m in match_data.iter() {
+        let (width, height) = m.tile.dimensions();
+        replace(
+            output,
+            &ImageBuffer::from_pixel(width, height, grout.colour),
+            m.x,
+            m.y,
+        );
+    }
+
+    let radius = grout.corner_radius as f64;
+    if grout.shadow > 0 {
+        let shadow = grout.shadow as f64;
+        let black = Rgb([0, 0, 0]);
+        for m in match_data.iter() {
+            let (offset_x, offset_y, width, height) =
+                grout.get_photo_rect(m.tile.width(), m.tile.height());
+            let left = (m.x + offset_x) as f64 + shadow;
+            let top = (m.y + offset_y) as f64 + shadow;
+            let right = (m.x + offset_x + width + 2 * grout.shadow).min(output.width());
+            let bottom = (m.y + offset_y + height + 2 * grout.shadow).min(output.height());
+            for y in m.y + offset_y..bottom {
+                for x in m.x + offset_x..right {
+                    let distance =
+                        get_distance(x, y, left, top, width as f64, height as f64, radius);
+                    let amount = (0.5 - distance / shadow).clamp(0.0, 1.0) * SHADOW_OPACITY;
+                    if amount > 0.0 {
+                        mix(output.get_pixel_mut(x, y), black, amount);
+                    }
+                }
+            }
+        }
+    }
+
+    for m in match_data.iter() {
+        let (offset_x, offset_y, width, height) =
+            grout.get_photo_rect(m.tile.width(), m.tile.height());
+        for y in offset_y..offset_y + height {
+            for x in offset_x..offset_x + width {
+                let distance = get_distance(
+                    x,
+                    y,
+                    offset_x as f64,
+                    offset_y as f64,
+                    width as f64,
+                    height as f64,
+                    radius,
+                );
+                let coverage = (0.5 - distance).clamp(0.0, 1.0);
+                if coverage > 0.0 {
+                    mix(
+                        output.get_pixel_mut(m.x + x, m.y + y),
+                        *m.tile.get_pixel(x, y),
+                        coverage,
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_config(width: u32) -> GroutConfig {
+        GroutConfig {
+            width,
+            ..GroutConfig::default()
+        }
+    }
+
+    #[test]
+    fn photo_rect() {
+        assert_eq!(get_config(4).get_photo_rect(20, 10), (2, 2, 16, 6));
+        assert_eq!(get_config(5).get_photo_rect(20, 10), (2, 2, 15, 5));
+    }
+
+    #[test]
+    fn no_grout_fills_the_cell() {
+        assert_eq!(get_config(0).get_photo_rect(20, 10), (0, 0, 20, 10));
+    }
+
+    #[test]
+    fn small_cells_keep_a_pixel() {
+        assert_eq!(get_config(4).get_photo_rect(3, 3), (1, 1, 1, 1));
+        assert_eq!(get_config(10).get_photo_rect(1, 1), (0, 0, 1, 1));
+    }
+}
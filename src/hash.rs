@@ -0,0 +1,290 @@
+use std::cmp::Reverse;
+use std::f64::consts::PI;
+use std::path::PathBuf;
+
+use image::imageops::{grayscale, resize};
+use image::{ImageBuffer, Luma, Rgb};
+
+use crate::{Library, PicData};
+
+/// Hashes differing in no more bits than this, each of the three, are taken
+/// to be of the same photo when none is asked for.
+pub const DEFAULT_DUPLICATE_DISTANCE: u32 = 6;
+
+/// Side of the greyscale image `PerceptualHashes::perceptual` is taken from.
+const DCT_SIZE: u32 = 32;
+
+/// Three 64 bit fingerprints of a photo that barely change when it is
+/// resized, recompressed or slightly recoloured, so copies of the same photo
+/// have hashes only a few bits apart.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PerceptualHashes {
+    /// aHash: which pixels of an 8x8 greyscale copy are above its mean.
+    pub average: u64,
+    /// dHash: which pixels of a 9x8 greyscale copy are darker than the
+    /// pixel to their right.
+    pub difference: u64,
+    /// pHash: which of the lowest 8x8 frequencies of a 32x32 greyscale copy
+    /// are above their median.
+    pub perceptual: u64,
+}
+
+impl PerceptualHashes {
+    pub fn new(img: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Self {
+        let grey = resize(
+            &grayscale(img),
+            DCT_SIZE,
+            DCT_SIZE,
+            image::FilterType::Triangle,
+        );
+        PerceptualHashes {
+            average: get_average_hash(&grey),
+            difference: get_difference_hash(&grey),
+            perceptual: get_perceptual_hash(&grey),
+        }
+    }
+
+    /// Returns the largest number of bits any of the three hashes differ in.
+    pub fn get_distance(&self, other: &PerceptualHashes) -> u32 {
+        (self.average ^ other.average)
+            .count_ones()
+            .max((self.difference ^ other.difference).count_ones())
+            .max((self.perceptual ^ other.perceptual).count_ones())
+    }
+}
+
+fn get_bits(values: impl Iterator<Item = bool>) -> u64 {
+    values.fold(0, |hash, bit| hash << 1 | bit as u64)
+}
+
+fn get_average_hash(grey: &ImageBuffer<Luma<u8>, Vec<u8>>) -> u64 {
+    let small = resize(grey, 8, 8, image::FilterType::Triangle);
+    let mean = small
+        .pixels()
+        .map(|pixel| pixel.data[0] as u32)
+        .sum::<u32>()
+        / 64;
+    get_bits(small.pixels().map(|pixel| pixel.data[0] as u32 > mean))
+}
+
+fn get_difference_hash(grey: &ImageBuffer<Luma<u8>, Vec<u8>>) -> u64 {
+    let small = resize(grey, 9, 8, image::FilterType::Triangle);
+    get_bits((0..8).flat_map(|y| {
+        let small = &small;
+        (0..8).map(move |x| small.get_pixel(x, y).data[0] < small.get_pixel(x + 1, y).data[0])
+    }))
+}
+
+/// Type-II discrete cosine transform of `values`.
+fn get_dct(values: &[f64]) -> Vec<f64> {
+    let n = values.len() as f64;
+    (0..values.len())
+        .map(|k| {
+            values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| value * (PI / n * (i as f64 + 0.5) * k as f64).cos())
+                .sum()
+        })
+        .collect()
+}
+
+fn get_perceptual_hash(grey: &ImageBuffer<Luma<u8>, Vec<u8>>) -> u64 {
+    let size = DCT_SIZE as usize;
+    let rows: Vec<Vec<f64>> = (0..DCT_SIZE)
+        .map(|y| {
+            let row: Vec<f64> = (0..DCT_SIZE)
+                .map(|x| grey.get_pixel(x, y).data[0] as f64)
+                .collect();
+            get_dct(&row)
+        })
+        .collect();
+    // Only the lowest frequencies are kept, so only their columns are needed.
+    let columns: Vec<Vec<f64>> = (0..8)
+        .map(|x| get_dct(&(0..size).map(|y| rows[y][x]).collect::<Vec<_>>()))
+        .collect();
+    let low: Vec<f64> = (0..8)
+        .flat_map(|y| columns.iter().map(move |column| column[y]))
+        .collect();
+
+    // The first value is the mean brightness, which says nothing about the
+    // picture's structure.
+    let mut sorted = low[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = sorted[sorted.len() / 2];
+    get_bits(low.iter().map(|value| *value > median))
+}
+
+/// Library photos that are copies of one another, and the one kept.
+#[derive(Clone, Debug)]
+pub struct DuplicateGroup {
+    pub kept: PathBuf,
+    pub duplicates: Vec<PathBuf>,
+}
+
+impl Library {
+    /// Groups each photo with the copies of it: photos whose hashes are all
+    /// within `max_distance` bits of its own. Photos are taken pinned ones
+    /// first, then the ones with the most pixels, and each either joins the
+    /// first group whose kept photo it is that close to or is kept in a new
+    /// one, so a group never chains together photos that only resemble one
+    /// another through a third. Excluded photos are left out.
+    pub fn find_duplicates(&self, max_distance: u32) -> Vec<DuplicateGroup> {
+        let mut pics_data: Vec<_> = self
+            .pics_data
+            .iter()
+            .filter(|pic_data| !self.is_excluded(&pic_data.path))
+            .collect();
+        pics_data.sort_by_key(|pic_data| {
+            let (width, height) = pic_data.dimensions;
+            (
+                Reverse(self.is_pinned(&pic_data.path)),
+                Reverse(width as u64 * height as u64),
+                pic_data.path.clone(),
+            )
+        });
+
+        let mut groups: Vec<(&PicData, Vec<PathBuf>)> = Vec::new();
+        for pic_data in pics_data {
+            let group = groups
+                .iter_mut()
+                .find(|(kept, _)| kept.hashes.get_distance(&pic_data.hashes) <= max_distance);
+            match group {
+                Some((_, duplicates)) => duplicates.push(pic_data.path.clone()),
+                None => groups.push((pic_data, Vec::new())),
+            }
+        }
+
+        let mut duplicate_groups: Vec<DuplicateGroup> = groups
+            .into_iter()
+            .filter(|(_, duplicates)| !duplicates.is_empty())
+            .map(|(kept, duplicates)| DuplicateGroup {
+                kept: kept.path.clone(),
+                duplicates,
+            })
+            .collect();
+        duplicate_groups.sort_by(|a, b| a.kept.cmp(&b.kept));
+
+        duplicate_groups
+    }
+
+    /// Excludes every photo but the one kept from each group of copies, see
+    /// `find_duplicates`, and returns the groups.
+    pub fn dedupe(&mut self, max_distance: u32) -> Vec<DuplicateGroup> {
+        let groups = self.find_duplicates(max_distance);
+        for group in groups.iter() {
+            for path in group.duplicates.iter() {
+                self.exclude(path.clone());
+            }
+        }
+
+        groups
+    }
+}
+
+/// Writes `groups` as text, each photo kept followed by its copies.
+pub fn format_duplicate_report(groups: &[DuplicateGroup]) -> String {
+    let duplicates: usize = groups.iter().map(|group| group.duplicates.len()).sum();
+    let mut text = format!(
+        "{} duplicates of {} photos excluded\n",
+        duplicates,
+        groups.len()
+    );
+    for group in groups.iter() {
+        text += &format!("kept {}\n", group.kept.display());
+        for path in group.duplicates.iter() {
+            text += &format!("  excluded {}\n", path.display());
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// A library photo with only what `find_duplicates` looks at filled in.
+    fn get_pic_data(name: &str, pixels: u32, average: u64) -> PicData {
+        PicData {
+            path: PathBuf::from(name),
+            dimensions: (pixels, 1),
+            aspect: pixels as f64,
+            hashes: PerceptualHashes {
+                average,
+                ..PerceptualHashes::default()
+            },
+            preview: ImageBuffer::new(1, 1),
+            cropped: Vec::new(),
+            coarse: Vec::new(),
+            medium: Vec::new(),
+            channel_sums: Vec::new(),
+        }
+    }
+
+    /// Three photos, each 3 bits from the next but 6 from the first to the
+    /// last, the largest first.
+    fn get_library() -> Library {
+        let mut library = Library::new(8);
+        library.pics_data = vec![
+            get_pic_data("a.png", 300, 0),
+            get_pic_data("b.png", 200, 0b111),
+            get_pic_data("c.png", 100, 0b111111),
+        ];
+        library
+    }
+
+    #[test]
+    fn distance_is_the_largest_of_the_hashes() {
+        let hashes = PerceptualHashes {
+            average: 0b1,
+            difference: 0b111,
+            perceptual: 0b11,
+        };
+        assert_eq!(hashes.get_distance(&hashes), 0);
+        assert_eq!(hashes.get_distance(&PerceptualHashes::default()), 3);
+    }
+
+    #[test]
+    fn groups_do_not_chain_through_a_third_photo() {
+        let groups = get_library().find_duplicates(3);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].kept, PathBuf::from("a.png"));
+        assert_eq!(groups[0].duplicates, vec![PathBuf::from("b.png")]);
+    }
+
+    #[test]
+    fn excluded_photos_are_left_out() {
+        let mut library = get_library();
+        library.exclude(PathBuf::from("a.png"));
+        let groups = library.find_duplicates(3);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].kept, PathBuf::from("b.png"));
+        assert_eq!(groups[0].duplicates, vec![PathBuf::from("c.png")]);
+    }
+
+    #[test]
+    fn pinned_photos_are_kept_first() {
+        let mut library = get_library();
+        library.pin(PathBuf::from("c.png"));
+        let groups = library.find_duplicates(6);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].kept, PathBuf::from("c.png"));
+        assert_eq!(
+            groups[0].duplicates,
+            vec![PathBuf::from("a.png"), PathBuf::from("b.png")]
+        );
+    }
+
+    #[test]
+    fn dedupe_excludes_only_the_duplicates() {
+        let mut library = get_library();
+        let groups = library.dedupe(3);
+        assert_eq!(groups.len(), 1);
+        assert!(!library.is_excluded(Path::new("a.png")));
+        assert!(library.is_excluded(Path::new("b.png")));
+        assert!(!library.is_excluded(Path::new("c.png")));
+        assert_eq!(library.usable_count(), 2);
+    }
+}
@@ -0,0 +1,225 @@
+//! Sum of absolute differences over raw `u8` buffers, the inner loop of every
+//! colour comparison. On x86_64 this uses AVX2 when the CPU has it and SSE2
+//! otherwise; other targets get a scalar loop the compiler can vectorize.
+
+/// Returns the sum of `|a[i] - b[i]|` over the shorter of the two slices.
+pub fn get_sad(a: &[u8], b: &[u8]) -> u64 {
+    let len = a.len().min(b.len());
+    let (a, b) = (&a[..len], &b[..len]);
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // Safety: AVX2 support was just checked.
+            return unsafe { get_sad_avx2(a, b) };
+        }
+        // Safety: SSE2 is part of the x86_64 baseline.
+        return unsafe { get_sad_sse2(a, b) };
+    }
+
+    #[allow(unreachable_code)]
+    get_sad_scalar(a, b)
+}
+
+/// Portable fallback, also used for the tails the vector paths leave over.
+pub fn get_sad_scalar(a: &[u8], b: &[u8]) -> u64 {
+    // Sum in u32 chunks so the loop vectorizes, 2^16 * 255 cannot overflow.
+    let mut sum = 0;
+    for (a, b) in a.chunks(1 << 16).zip(b.chunks(1 << 16)) {
+        let chunk: u32 = a
+            .iter()
+            .zip(b.iter())
+            .map(|(&x, &y)| (x.max(y) - x.min(y)) as u32)
+            .sum();
+        sum += chunk as u64;
+    }
+
+    sum
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn get_sad_sse2(a: &[u8], b: &[u8]) -> u64 {
+    use std::arch::x86_64::*;
+
+    let chunks = a.len() / 16;
+    let mut total = _mm_setzero_si128();
+    for i in 0..chunks {
+        let x = _mm_loadu_si128(a.as_ptr().add(i * 16) as *const __m128i);
+        let y = _mm_loadu_si128(b.as_ptr().add(i * 16) as *const __m128i);
+        // Two u64 lanes, each holding the sum of eight byte differences.
+        total = _mm_add_epi64(total, _mm_sad_epu8(x, y));
+    }
+
+    let mut lanes = [0u64; 2];
+    _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, total);
+    let tail = chunks * 16;
+    lanes[0] + lanes[1] + get_sad_scalar(&a[tail..], &b[tail..])
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn get_sad_avx2(a: &[u8], b: &[u8]) -> u64 {
+    use std::arch::x86_64::*;
+
+    let chunks = a.len() / 32;
+    let mut total = _mm256_setzero_si256();
+    for i in 0..chunks {
+        let x = _mm256_loadu_si256(a.as_ptr().add(i * 32) as *const __m256i);
+        let y = _mm256_loadu_si256(b.as_ptr().add(i * 32) as *const __m256i);
+        total = _mm256_add_epi64(total, _mm256_sad_epu8(x, y));
+    }
+
+    let mut lanes = [0u64; 4];
+    _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, total);
+    let tail = chunks * 32;
+    lanes.iter().sum::<u64>() + get_sad_scalar(&a[tail..], &b[tail..])
+}
+
+/// Bytes compared between checks in `get_sad_bounded`, 256 RGB pixels.
+const BOUNDED_BLOCK: usize = 768;
+
+/// Like `get_sad`, but stops and returns `None` as soon as the running sum
+/// exceeds `bound`.
+pub fn get_sad_bounded(a: &[u8], b: &[u8], bound: u64) -> Option<u64> {
+    let mut sum = 0;
+    for (a, b) in a.chunks(BOUNDED_BLOCK).zip(b.chunks(BOUNDED_BLOCK)) {
+        sum += get_sad(a, b);
+        if sum > bound {
+            return None;
+        }
+    }
+
+    Some(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Lengths either side of the vector widths and of the scalar chunk size.
+    const LENGTHS: [usize; 14] = [
+        0,
+        1,
+        7,
+        15,
+        16,
+        17,
+        31,
+        32,
+        33,
+        63,
+        64,
+        100,
+        1000,
+        (1 << 16) + 45,
+    ];
+
+    fn get_buffers(len: usize, seed: u64) -> (Vec<u8>, Vec<u8>) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let a = (0..len).map(|_| rng.gen()).collect();
+        let b = (0..len).map(|_| rng.gen()).collect();
+        (a, b)
+    }
+
+    fn get_sad_naive(a: &[u8], b: &[u8]) -> u64 {
+        a.iter()
+            .zip(b.iter())
+            .map(|(&x, &y)| (x as i64 - y as i64).unsigned_abs())
+            .sum()
+    }
+
+    #[test]
+    fn scalar_matches_naive() {
+        for (seed, &len) in LENGTHS.iter().enumerate() {
+            let (a, b) = get_buffers(len, seed as u64);
+            assert_eq!(
+                get_sad_scalar(&a, &b),
+                get_sad_naive(&a, &b),
+                "length {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn extremes_do_not_overflow() {
+        let a = vec![255; (1 << 16) * 3 + 5];
+        let b = vec![0; a.len()];
+        let expected = 255 * a.len() as u64;
+        assert_eq!(get_sad_scalar(&a, &b), expected);
+        assert_eq!(get_sad(&a, &b), expected);
+        assert_eq!(get_sad(&b, &a), expected);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn sse2_matches_scalar() {
+        for (seed, &len) in LENGTHS.iter().enumerate() {
+            let (a, b) = get_buffers(len, seed as u64);
+            // Safety: SSE2 is part of the x86_64 baseline.
+            let sad = unsafe { get_sad_sse2(&a, &b) };
+            assert_eq!(sad, get_sad_scalar(&a, &b), "length {}", len);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn avx2_matches_scalar() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        for (seed, &len) in LENGTHS.iter().enumerate() {
+            let (a, b) = get_buffers(len, seed as u64);
+            // Safety: AVX2 support was just checked.
+            let sad = unsafe { get_sad_avx2(&a, &b) };
+            assert_eq!(sad, get_sad_scalar(&a, &b), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn unequal_lengths_use_the_shorter() {
+        let (a, b) = get_buffers(100, 1);
+        assert_eq!(get_sad(&a[..37], &b), get_sad_scalar(&a[..37], &b[..37]));
+        assert_eq!(get_sad(&a, &b[..37]), get_sad_scalar(&a[..37], &b[..37]));
+    }
+
+    #[test]
+    fn bounded_matches_unbounded() {
+        let lengths = [
+            0,
+            1,
+            BOUNDED_BLOCK - 1,
+            BOUNDED_BLOCK,
+            BOUNDED_BLOCK + 1,
+            BOUNDED_BLOCK * 5 + 13,
+        ];
+        for &len in lengths.iter() {
+            let (a, b) = get_buffers(len, len as u64);
+            let sad = get_sad(&a, &b);
+            assert_eq!(
+                get_sad_bounded(&a, &b, u64::MAX),
+                Some(sad),
+                "length {}",
+                len
+            );
+            assert_eq!(get_sad_bounded(&a, &b, sad), Some(sad), "length {}", len);
+            if sad > 0 {
+                assert_eq!(get_sad_bounded(&a, &b, sad - 1), None, "length {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn bounded_stops_early() {
+        // Every difference is in the first block, so the bound is exceeded
+        // before the rest is compared.
+        let mut a = vec![0; BOUNDED_BLOCK * 4];
+        let b = vec![0; a.len()];
+        a[0] = 200;
+        assert_eq!(get_sad_bounded(&a, &b, 199), None);
+        assert_eq!(get_sad_bounded(&a, &b, 200), Some(200));
+    }
+}
@@ -1,67 +1,848 @@
-use image::imageops::resize;
+use image::imageops::{
+    crop, flip_horizontal, flip_vertical, resize, rotate180, rotate270, rotate90,
+};
 use image::{ImageBuffer, Rgb};
-use std::path::PathBuf;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+mod alpha;
+mod cascade;
+mod colour;
+mod coverage;
+mod engine;
+mod gaps;
+mod grout;
+mod hash;
+mod kernel;
+mod orientation;
+mod progress;
+mod project;
+mod settings;
+mod text;
+
+pub use alpha::*;
+pub use cascade::*;
+pub use colour::*;
+pub use coverage::*;
+pub use engine::*;
+pub use gaps::*;
+pub use grout::*;
+pub use hash::*;
+pub use kernel::*;
+pub use orientation::*;
+pub use progress::*;
+pub use project::*;
+pub use settings::*;
+pub use text::*;
+
+/// Thumbnail size used when none is asked for. Small cells match nearly as
+/// well at 8 or 16 and are scored far faster.
+pub const DEFAULT_THUMB_SIZE: u32 = 128;
+
+/// Longer side of `PicData::preview`, whatever the thumbnail size.
+pub const PREVIEW_SIZE: u32 = 64;
+
+/// Aspects that library photos are pre-cropped to for matching. Each cell is
+/// scored against the bucket closest to its own aspect.
+pub const ASPECT_BUCKETS: [f64; 5] = [0.5, 0.75, 1.0, 4.0 / 3.0, 2.0];
 
 #[derive(Clone, Debug)]
 pub struct PicData {
     pub path: PathBuf,
+    /// Width and height of the photo as loaded.
+    pub dimensions: (u32, u32),
     pub aspect: f64,
-    pub thumbnail: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    /// Fingerprints for spotting copies of the photo, see `find_duplicates`.
+    pub hashes: PerceptualHashes,
+    /// The whole photo scaled to `PREVIEW_SIZE` on its longer side, for
+    /// showing it and for its overall colours rather than matching.
+    pub preview: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    /// Thumbnails of the centre crop at each of `ASPECT_BUCKETS`.
+    pub cropped: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+    /// `cropped` scaled down to `COARSE_THUMB_SIZE` and `MEDIUM_THUMB_SIZE`.
+    pub coarse: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+    pub medium: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+    /// Per-channel totals of each `cropped` thumbnail, see `get_channel_sums`.
+    pub channel_sums: Vec<[u64; 3]>,
+}
+
+/// The indexed library photos. Every thumbnail in it is `thumb_size` square,
+/// and cells must be thumbnailed to the same size before matching.
+#[derive(Clone, Debug)]
+pub struct Library {
+    /// Folders the photos were read from.
+    pub dirs: Vec<PathBuf>,
+    pub thumb_size: u32,
+    pub pics_data: Vec<PicData>,
+    /// Photos that matching never uses.
+    pub excluded: HashSet<PathBuf>,
+    /// Photos that every mosaic uses at least once, in their best cells.
+    pub pinned: Vec<PathBuf>,
+}
+
+impl Library {
+    pub fn new(thumb_size: u32) -> Self {
+        Library {
+            dirs: Vec::new(),
+            thumb_size,
+            pics_data: Vec::new(),
+            excluded: HashSet::new(),
+            pinned: Vec::new(),
+        }
+    }
+
+    /// Returns the path of the library photo `name` refers to, which can be
+    /// its full path or just its last components, e.g. its file name.
+    pub fn find_path(&self, name: &Path) -> Option<PathBuf> {
+        self.pics_data
+            .iter()
+            .find(|pic_data| pic_data.path.ends_with(name))
+            .map(|pic_data| pic_data.path.clone())
+    }
+
+    /// Stops `path` being used, unpinning it if it was pinned.
+    pub fn exclude(&mut self, path: PathBuf) {
+        self.pinned.retain(|pinned| *pinned != path);
+        self.excluded.insert(path);
+    }
+
+    /// Lets matching use `path` again after it was excluded.
+    pub fn include(&mut self, path: &Path) {
+        self.excluded.remove(path);
+    }
+
+    /// Makes sure `path` is used, including it again if it was excluded.
+    pub fn pin(&mut self, path: PathBuf) {
+        self.excluded.remove(&path);
+        if !self.pinned.contains(&path) {
+            self.pinned.push(path);
+        }
+    }
+
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.excluded.contains(path)
+    }
+
+    pub fn is_pinned(&self, path: &Path) -> bool {
+        self.pinned.iter().any(|pinned| pinned == path)
+    }
+
+    /// Number of photos matching may use.
+    pub fn usable_count(&self) -> usize {
+        self.pics_data
+            .iter()
+            .filter(|pic_data| !self.is_excluded(&pic_data.path))
+            .count()
+    }
+
+    pub fn get_thumbnail(
+        &self,
+        img: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    ) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        resize(
+            img,
+            self.thumb_size,
+            self.thumb_size,
+            image::FilterType::Lanczos3,
+        )
+    }
 }
 
-pub fn get_pic_data(path: PathBuf) -> Option<PicData> {
-    match image::open(&path) {
+pub fn get_pic_data(path: PathBuf, thumb_size: u32) -> Option<PicData> {
+    match open_image(&path) {
         Ok(img) => {
-            let img = img.to_rgb();   
+            let img = flatten(&img, BACKGROUND);
             let aspect = img.width() as f64 / img.height() as f64;
-            let thumbnail = resize(&img, 128, 128, image::FilterType::Lanczos3);
+            let cropped: Vec<_> = ASPECT_BUCKETS
+                .iter()
+                .map(|&bucket| {
+                    let crop = crop_to_aspect(&img, bucket);
+                    resize(&crop, thumb_size, thumb_size, image::FilterType::Lanczos3)
+                })
+                .collect();
+            let coarse = cropped.iter().map(get_coarse_thumbnail).collect();
+            let medium = cropped.iter().map(get_medium_thumbnail).collect();
+            let channel_sums = cropped.iter().map(get_channel_sums).collect();
+            let hashes = PerceptualHashes::new(&img);
+            let preview_scale = PREVIEW_SIZE as f64 / img.width().max(img.height()) as f64;
+            let preview = resize(
+                &img,
+                ((img.width() as f64 * preview_scale).round() as u32).max(1),
+                ((img.height() as f64 * preview_scale).round() as u32).max(1),
+                image::FilterType::Triangle,
+            );
 
-            Some(PicData { path: path, aspect: aspect, thumbnail: thumbnail, })   
-        },
+            Some(PicData {
+                path,
+                dimensions: img.dimensions(),
+                aspect,
+                hashes,
+                preview,
+                cropped,
+                coarse,
+                medium,
+                channel_sums,
+            })
+        }
         Err(_) => None,
     }
 }
 
-#[derive(Debug)]
+/// Indexes every picture in `pics_dir`, skipping files that can't be read
+/// as images.
+pub fn get_library(
+    pics_dir: &Path,
+    thumb_size: u32,
+    progress: &dyn ProgressSink,
+) -> io::Result<Library> {
+    get_merged_library(&[pics_dir.to_path_buf()], thumb_size, progress)
+}
+
+/// Indexes every picture in each of `pics_dirs` into a single library.
+pub fn get_merged_library(
+    pics_dirs: &[PathBuf],
+    thumb_size: u32,
+    progress: &dyn ProgressSink,
+) -> io::Result<Library> {
+    let mut paths = Vec::new();
+    for pics_dir in pics_dirs.iter() {
+        for entry in fs::read_dir(pics_dir)? {
+            paths.push(entry?.path());
+        }
+    }
+
+    let counter = ProgressCounter::new(paths.len());
+    let loaded = AtomicUsize::new(0);
+    let pics_data: Vec<Option<PicData>> = paths
+        .into_par_iter()
+        .map(|path| {
+            let pic_data = get_pic_data(path, thumb_size);
+            if pic_data.is_some() {
+                loaded.fetch_add(1, Ordering::SeqCst);
+            }
+            progress.library_scanned(loaded.load(Ordering::SeqCst), counter.step());
+            pic_data
+        })
+        .collect();
+
+    let mut library = Library::new(thumb_size);
+    library.dirs = pics_dirs.to_vec();
+    library.pics_data = pics_data.into_iter().flatten().collect();
+
+    Ok(library)
+}
+
+/// Returns the index into `ASPECT_BUCKETS` closest to `aspect`.
+pub fn get_aspect_bucket(aspect: f64) -> usize {
+    let mut best_bucket = 0;
+    let mut best_distance = f64::INFINITY;
+    for (i, bucket) in ASPECT_BUCKETS.iter().enumerate() {
+        // Compare in log space so 0.5 and 2.0 are equally far from 1.0.
+        let distance = (aspect.ln() - bucket.ln()).abs();
+        if distance < best_distance {
+            best_bucket = i;
+            best_distance = distance;
+        }
+    }
+
+    best_bucket
+}
+
+/// Returns the largest centred region of `img` with the given aspect.
+pub fn crop_to_aspect(
+    img: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    aspect: f64,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let (width, height) = img.dimensions();
+    let (crop_width, crop_height) = if (width as f64 / height as f64) > aspect {
+        (
+            ((height as f64 * aspect).round() as u32).max(1).min(width),
+            height,
+        )
+    } else {
+        (
+            width,
+            ((width as f64 / aspect).round() as u32).max(1).min(height),
+        )
+    };
+    let x = (width - crop_width) / 2;
+    let y = (height - crop_height) / 2;
+
+    let mut img = img.clone();
+    crop(&mut img, x, y, crop_width, crop_height).to_image()
+}
+
+/// Crops `img` to the aspect of the cell and scales it to fill the cell
+/// exactly. This is the region `find_best_match` scores against.
+pub fn fill_tile(
+    img: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    width: u32,
+    height: u32,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let crop = crop_to_aspect(img, width as f64 / height as f64);
+    resize(&crop, width, height, image::FilterType::Lanczos3)
+}
+
+/// A mirror or rotation applied to a library photo before it is placed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transform {
+    Identity,
+    FlipHorizontal,
+    FlipVertical,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Transform {
+    pub const ALL: [Transform; 6] = [
+        Transform::Identity,
+        Transform::FlipHorizontal,
+        Transform::FlipVertical,
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Transform::Identity => "identity",
+            Transform::FlipHorizontal => "flip-horizontal",
+            Transform::FlipVertical => "flip-vertical",
+            Transform::Rotate90 => "rotate-90",
+            Transform::Rotate180 => "rotate-180",
+            Transform::Rotate270 => "rotate-270",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Transform> {
+        Transform::ALL
+            .iter()
+            .copied()
+            .find(|transform| transform.name() == name)
+    }
+
+    /// Whether the transform exchanges width and height.
+    pub fn swaps_axes(self) -> bool {
+        self == Transform::Rotate90 || self == Transform::Rotate270
+    }
+
+    pub fn inverse(self) -> Transform {
+        match self {
+            Transform::Rotate90 => Transform::Rotate270,
+            Transform::Rotate270 => Transform::Rotate90,
+            other => other,
+        }
+    }
+
+    pub fn apply(self, img: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        match self {
+            Transform::Identity => img.clone(),
+            Transform::FlipHorizontal => flip_horizontal(img),
+            Transform::FlipVertical => flip_vertical(img),
+            Transform::Rotate90 => rotate90(img),
+            Transform::Rotate180 => rotate180(img),
+            Transform::Rotate270 => rotate270(img),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct MatchData {
     pub x: u32,
     pub y: u32,
+    pub path: PathBuf,
+    pub transform: Transform,
     pub tile: ImageBuffer<Rgb<u8>, Vec<u8>>,
 }
 
+/// Weights `find_best_match` uses to combine the individual scores of a
+/// candidate. Every score is roughly in `0.0..=1.0`, lower being better.
+#[derive(Clone, Debug)]
+pub struct ScoreConfig {
+    /// Difference between the cell aspect and the photo's own aspect.
+    pub aspect_weight: f64,
+    /// Per-pixel colour difference, see `get_pixel_score`.
+    pub colour_weight: f64,
+    /// Difference in mean brightness, see `get_brightness_score`.
+    pub brightness_weight: f64,
+    /// Difference in local contrast, see `get_edge_score`.
+    pub edge_weight: f64,
+}
+
+impl Default for ScoreConfig {
+    fn default() -> Self {
+        ScoreConfig {
+            aspect_weight: 0.4,
+            colour_weight: 0.6,
+            brightness_weight: 0.0,
+            edge_weight: 0.0,
+        }
+    }
+}
+
+impl ScoreConfig {
+    pub fn get_score(
+        &self,
+        aspect: f64,
+        pic_aspect: f64,
+        thumb1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        thumb2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    ) -> f64 {
+        let mut score = self.aspect_weight * (aspect - pic_aspect).abs();
+        if self.colour_weight != 0.0 {
+            score += self.colour_weight * get_pixel_score(thumb1, thumb2);
+        }
+        if self.brightness_weight != 0.0 {
+            score += self.brightness_weight * get_brightness_score(thumb1, thumb2);
+        }
+        if self.edge_weight != 0.0 {
+            score += self.edge_weight * get_edge_score(thumb1, thumb2);
+        }
+
+        score
+    }
+
+    /// Same as `get_score`, but returns `None` as soon as the score is known
+    /// to exceed `bound`, skipping the rest of the comparison. Weights are
+    /// assumed not to be negative.
+    pub fn get_score_bounded(
+        &self,
+        aspect: f64,
+        pic_aspect: f64,
+        thumb1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        thumb2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        bound: f64,
+    ) -> Option<f64> {
+        let mut score = self.aspect_weight * (aspect - pic_aspect).abs();
+        if score > bound {
+            return None;
+        }
+        if self.colour_weight != 0.0 {
+            let samples = (thumb1.width() * thumb1.height() * 3) as f64;
+            let sad_bound = (bound - score) / self.colour_weight * samples * PIXEL_SCORE_SCALE;
+            let sad_bound = if sad_bound.is_finite() {
+                sad_bound.ceil() as u64
+            } else {
+                u64::MAX
+            };
+            let sad = get_sad_bounded(thumb1, thumb2, sad_bound)?;
+            score += self.colour_weight * sad as f64 / samples / PIXEL_SCORE_SCALE;
+            if score > bound {
+                return None;
+            }
+        }
+        if self.brightness_weight != 0.0 {
+            score += self.brightness_weight * get_brightness_score(thumb1, thumb2);
+            if score > bound {
+                return None;
+            }
+        }
+        if self.edge_weight != 0.0 {
+            score += self.edge_weight * get_edge_score(thumb1, thumb2);
+            if score > bound {
+                return None;
+            }
+        }
+
+        Some(score)
+    }
+
+    /// A cheap score that `get_score` is never below, from the aspect term
+    /// and the colour term's lower bound given by the channel totals.
+    pub fn get_lower_bound(
+        &self,
+        aspect: f64,
+        pic_aspect: f64,
+        sums1: &[u64; 3],
+        sums2: &[u64; 3],
+        samples: u64,
+    ) -> f64 {
+        // |sum(a) - sum(b)| <= sum(|a - b|) for each channel.
+        let sad_bound: u64 = (0..3)
+            .map(|c| sums1[c].max(sums2[c]) - sums1[c].min(sums2[c]))
+            .sum();
+        let colour_bound = sad_bound as f64 / (samples * 3) as f64 / PIXEL_SCORE_SCALE;
+        self.aspect_weight * (aspect - pic_aspect).abs() + self.colour_weight * colour_bound
+    }
+}
+
+/// Returns the total of each colour channel over `thumbnail`.
+pub fn get_channel_sums(thumbnail: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> [u64; 3] {
+    let mut sums = [0; 3];
+    for pixel in thumbnail.pixels() {
+        for (sum, &value) in sums.iter_mut().zip(pixel.data.iter()) {
+            *sum += value as u64;
+        }
+    }
+
+    sums
+}
+
+/// Mean absolute channel difference that `get_pixel_score` maps to 1.0, so
+/// scores are comparable whatever the thumbnail size.
+pub const PIXEL_SCORE_SCALE: f64 = 7500000.0 / (128.0 * 128.0 * 3.0);
+
 pub fn get_pixel_score(
     thumb1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
     thumb2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
 ) -> f64 {
+    let score = get_sad(thumb1, thumb2) as f64;
+    let samples = (thumb1.width() * thumb1.height() * 3) as f64;
+    score / samples / PIXEL_SCORE_SCALE
+}
+
+fn get_luma(pixel: &Rgb<u8>) -> f64 {
+    0.299 * pixel.data[0] as f64 + 0.587 * pixel.data[1] as f64 + 0.114 * pixel.data[2] as f64
+}
+
+/// Difference in mean brightness, 0.0 for equal and 1.0 for black vs white.
+pub fn get_brightness_score(
+    thumb1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    thumb2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+) -> f64 {
+    let mut difference = 0.0;
+    for (p1, p2) in thumb1.pixels().zip(thumb2.pixels()) {
+        difference += get_luma(p1) - get_luma(p2);
+    }
+    let samples = (thumb1.width() * thumb1.height()) as f64;
+    (difference / samples).abs() / 255.0
+}
+
+/// Mean difference between the horizontal and vertical brightness gradients
+/// of two thumbnails, so busy photos prefer busy cells and flat prefer flat.
+pub fn get_edge_score(
+    thumb1: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    thumb2: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+) -> f64 {
+    let (width, height) = thumb1.dimensions();
+    if width < 2 || height < 2 {
+        return 0.0;
+    }
+
     let mut score = 0.0;
-    for i in 0..128 {
-        for j in 0..128 {
-            let p1 = thumb1.get_pixel(i, j);
-            let p2 = thumb2.get_pixel(i, j);
-            score += (p1.data[0] as f64 - p2.data[0] as f64).abs();
-            score += (p1.data[1] as f64 - p2.data[1] as f64).abs();
-            score += (p1.data[2] as f64 - p2.data[2] as f64).abs();
+    for y in 0..height - 1 {
+        for x in 0..width - 1 {
+            let l1 = get_luma(thumb1.get_pixel(x, y));
+            let l2 = get_luma(thumb2.get_pixel(x, y));
+            let dx1 = get_luma(thumb1.get_pixel(x + 1, y)) - l1;
+            let dx2 = get_luma(thumb2.get_pixel(x + 1, y)) - l2;
+            let dy1 = get_luma(thumb1.get_pixel(x, y + 1)) - l1;
+            let dy2 = get_luma(thumb2.get_pixel(x, y + 1)) - l2;
+            score += (dx1 - dx2).abs() + (dy1 - dy2).abs();
         }
     }
-    return score / 7500000.0; // normalize the score value a bit
+    let samples = ((width - 1) * (height - 1) * 2) as f64;
+    score / samples / 255.0
 }
 
+/// A cell thumbnail prepared for scoring library photos under one transform.
+/// Scoring a transformed photo against the cell is the same as scoring the
+/// photo against the inversely transformed cell, which only needs doing once.
+struct CellTarget {
+    transform: Transform,
+    bucket: usize,
+    thumbnail: ImageBuffer<Rgb<u8>, Vec<u8>>,
+}
+
+fn get_cell_targets(
+    aspect: f64,
+    thumbnail: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    transforms: bool,
+) -> Vec<CellTarget> {
+    let candidates: &[Transform] = if transforms {
+        &Transform::ALL
+    } else {
+        &[Transform::Identity]
+    };
+
+    candidates
+        .iter()
+        .map(|&transform| {
+            let source_aspect = if transform.swaps_axes() {
+                1.0 / aspect
+            } else {
+                aspect
+            };
+            CellTarget {
+                transform,
+                bucket: get_aspect_bucket(source_aspect),
+                thumbnail: transform.inverse().apply(thumbnail),
+            }
+        })
+        .collect()
+}
+
+/// Aspect of a library photo once `transform` has been applied to it.
+fn get_pic_aspect(pic_data: &PicData, transform: Transform) -> f64 {
+    if transform.swaps_axes() {
+        1.0 / pic_data.aspect
+    } else {
+        pic_data.aspect
+    }
+}
+
+/// A library photo, as it would be placed in a cell, with its score there.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub transform: Transform,
+    pub score: f64,
+}
+
+/// Finds the library photo that best fills a cell. With `transforms` set,
+/// every mirrored and rotated version of each photo is also considered.
+/// `None` if there is no photo to use.
 pub fn find_best_match(
     aspect: f64,
     thumbnail: &ImageBuffer<Rgb<u8>, Vec<u8>>,
-    pics_data: &Vec<PicData>,
-) -> PathBuf {
-    let mut best_match = None;
-    let mut best_score = 100.0;
-    for pic_data in pics_data.iter() {
-        let aspect_score = (aspect - pic_data.aspect).abs();
-        let pixel_score = get_pixel_score(&thumbnail, &pic_data.thumbnail);
-        let score = 0.4 * aspect_score + 0.6 * pixel_score;
-        if score < best_score {
-            best_match = Some(pic_data.path.clone());
-            best_score = score;
+    library: &Library,
+    config: &ScoreConfig,
+    transforms: bool,
+) -> Option<(PathBuf, Transform)> {
+    let best = find_top_matches(aspect, thumbnail, library, config, transforms, 1);
+    best.into_iter()
+        .next()
+        .map(|candidate| (candidate.path, candidate.transform))
+}
+
+/// Returns the `count` best candidates for a cell, best first, with each
+/// library photo appearing at most once in its best transform.
+pub fn find_top_matches(
+    aspect: f64,
+    thumbnail: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    library: &Library,
+    config: &ScoreConfig,
+    transforms: bool,
+    count: usize,
+) -> Vec<Candidate> {
+    find_top_matches_where(
+        aspect,
+        thumbnail,
+        library,
+        config,
+        transforms,
+        count,
+        &|_| true,
+    )
+}
+
+/// `find_top_matches` over only the photos `usable` accepts.
+pub(crate) fn find_top_matches_where(
+    aspect: f64,
+    thumbnail: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    library: &Library,
+    config: &ScoreConfig,
+    transforms: bool,
+    count: usize,
+    usable: &(dyn Fn(&PicData) -> bool + Sync),
+) -> Vec<Candidate> {
+    if count == 0 {
+        return Vec::new();
+    }
+    let targets = get_cell_targets(aspect, thumbnail, transforms);
+    let sums = get_channel_sums(thumbnail);
+    let samples = (thumbnail.width() * thumbnail.height()) as u64;
+
+    // Visit candidates from the lowest lower bound up, so good matches are
+    // found early and most of the rest are cut short or never scored.
+    let mut candidates = Vec::new();
+    for (i, pic_data) in library.pics_data.iter().enumerate() {
+        if library.is_excluded(&pic_data.path) || !usable(pic_data) {
+            continue;
+        }
+        for (j, target) in targets.iter().enumerate() {
+            let pic_aspect = get_pic_aspect(pic_data, target.transform);
+            let pic_sums = &pic_data.channel_sums[target.bucket];
+            let lower_bound = config.get_lower_bound(aspect, pic_aspect, &sums, pic_sums, samples);
+            candidates.push((lower_bound, i, j));
+        }
+    }
+    candidates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    // Sorted by score, then by library order so ties go to the earliest photo.
+    let mut best: Vec<(f64, usize, usize)> = Vec::new();
+    for (lower_bound, i, j) in candidates {
+        let bound = if best.len() < count {
+            f64::INFINITY
+        } else {
+            best[count - 1].0
+        };
+        if lower_bound > bound {
+            break;
+        }
+
+        let pic_data = &library.pics_data[i];
+        let target = &targets[j];
+        let pic_aspect = get_pic_aspect(pic_data, target.transform);
+        let pic_thumbnail = &pic_data.cropped[target.bucket];
+        if let Some(score) =
+            config.get_score_bounded(aspect, pic_aspect, &target.thumbnail, pic_thumbnail, bound)
+        {
+            let entry = (score, i, j);
+            if let Some(existing) = best.iter().position(|other| other.1 == i) {
+                if best[existing] < entry {
+                    continue;
+                }
+                best.remove(existing);
+            }
+            let position = best
+                .iter()
+                .position(|other| entry < *other)
+                .unwrap_or(best.len());
+            best.insert(position, entry);
+            best.truncate(count);
         }
     }
 
-    best_match.unwrap()
+    best.into_iter()
+        .map(|(score, i, j)| Candidate {
+            path: library.pics_data[i].path.clone(),
+            transform: targets[j].transform,
+            score,
+        })
+        .collect()
+}
+
+/// Scores a single library photo against a cell, returning it in its best
+/// transform.
+pub fn get_candidate(
+    aspect: f64,
+    thumbnail: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    pic_data: &PicData,
+    config: &ScoreConfig,
+    transforms: bool,
+) -> Candidate {
+    let mut best = Candidate {
+        path: pic_data.path.clone(),
+        transform: Transform::Identity,
+        score: f64::INFINITY,
+    };
+    for target in get_cell_targets(aspect, thumbnail, transforms) {
+        let pic_aspect = get_pic_aspect(pic_data, target.transform);
+        let pic_thumbnail = &pic_data.cropped[target.bucket];
+        let score = config.get_score(aspect, pic_aspect, &target.thumbnail, pic_thumbnail);
+        if score < best.score {
+            best.transform = target.transform;
+            best.score = score;
+        }
+    }
+
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::env;
+    use std::process;
+
+    /// Saves each of `pics` in a new folder named after the test and indexes
+    /// it.
+    fn get_test_library(name: &str, pics: &[ImageBuffer<Rgb<u8>, Vec<u8>>]) -> Library {
+        let dir = env::temp_dir().join(format!("mosaic-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (i, pic) in pics.iter().enumerate() {
+            pic.save(dir.join(format!("{}.png", i))).unwrap();
+        }
+        let library = get_library(&dir, 8, &NoProgress).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        library
+    }
+
+    /// A photo of random size with a random gradient and some noise, so its
+    /// orientation matters and no two photos score the same.
+    fn get_random_pic(rng: &mut StdRng) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let width = rng.gen_range(8..40);
+        let height = rng.gen_range(8..40);
+        let from: [u8; 3] = rng.gen();
+        let to: [u8; 3] = rng.gen();
+        ImageBuffer::from_fn(width, height, |x, y| {
+            let t = (x + y) as f64 / (width + height - 2) as f64;
+            let mut data = [0; 3];
+            for c in 0..3 {
+                let value = from[c] as f64 * (1.0 - t) + to[c] as f64 * t;
+                data[c] = (value + rng.gen_range(-20.0..20.0)).clamp(0.0, 255.0) as u8;
+            }
+            Rgb { data }
+        })
+    }
+
+    /// Every photo in its best transform, best first, scored in full.
+    fn get_ranking(
+        aspect: f64,
+        thumbnail: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        library: &Library,
+        config: &ScoreConfig,
+        transforms: bool,
+    ) -> Vec<Candidate> {
+        let mut ranking: Vec<_> = library
+            .pics_data
+            .iter()
+            .map(|pic_data| get_candidate(aspect, thumbnail, pic_data, config, transforms))
+            .collect();
+        ranking.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap());
+        ranking
+    }
+
+    #[test]
+    fn bounded_search_agrees_with_brute_force() {
+        let mut rng = StdRng::seed_from_u64(32);
+        let pics: Vec<_> = (0..24).map(|_| get_random_pic(&mut rng)).collect();
+        let library = get_test_library("brute-force", &pics);
+        let configs = [
+            ScoreConfig::default(),
+            ScoreConfig {
+                brightness_weight: 0.3,
+                edge_weight: 0.5,
+                ..ScoreConfig::default()
+            },
+            ScoreConfig {
+                aspect_weight: 0.0,
+                colour_weight: 0.0,
+                brightness_weight: 1.0,
+                edge_weight: 1.0,
+            },
+        ];
+
+        for _ in 0..20 {
+            let cell = get_random_pic(&mut rng);
+            let aspect = cell.width() as f64 / cell.height() as f64;
+            let thumbnail = library.get_thumbnail(&cell);
+            for config in configs.iter() {
+                for &transforms in [false, true].iter() {
+                    let ranking = get_ranking(aspect, &thumbnail, &library, config, transforms);
+                    let (path, transform) =
+                        find_best_match(aspect, &thumbnail, &library, config, transforms).unwrap();
+                    assert_eq!(path, ranking[0].path);
+                    assert_eq!(transform, ranking[0].transform);
+
+                    let top = find_top_matches(aspect, &thumbnail, &library, config, transforms, 5);
+                    assert_eq!(top.len(), 5);
+                    for (found, expected) in top.iter().zip(ranking.iter()) {
+                        assert_eq!(found.path, expected.path);
+                        assert_eq!(found.transform, expected.transform);
+                        assert!((found.score - expected.score).abs() < 1e-9);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn no_matches_for_zero_count() {
+        let pic = ImageBuffer::from_pixel(16, 12, Rgb { data: [255, 0, 0] });
+        let library = get_test_library("zero-count", &[pic]);
+        let thumbnail = ImageBuffer::from_pixel(8, 8, Rgb { data: [255, 0, 0] });
+        let config = ScoreConfig::default();
+
+        let matches = find_top_matches(1.0, &thumbnail, &library, &config, true, 0);
+        assert!(matches.is_empty());
+    }
 }
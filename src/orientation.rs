@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::DynamicImage;
+
+/// Returns the EXIF orientation of the photo at `path`, from 1 for upright
+/// to 8, or 1 if it has none or it can't be read.
+pub fn get_orientation(path: &Path) -> u32 {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return 1,
+    };
+    let exif = match exif::Reader::new().read_from_container(&mut BufReader::new(file)) {
+        Ok(exif) => exif,
+        Err(_) => return 1,
+    };
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .filter(|orientation| (1..=8).contains(orientation))
+        .unwrap_or(1)
+}
+
+/// Turns `img`, stored with the given EXIF orientation, upright.
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Opens the image at `path` like `image::open`, turned upright as its EXIF
+/// orientation says. Phone photos are often stored sideways with only this
+/// tag to say so.
+pub fn open_image(path: &Path) -> image::ImageResult<DynamicImage> {
+    let img = image::open(path)?;
+    Ok(apply_orientation(img, get_orientation(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Luma};
+
+    /// Where a stored pixel ends up once its image is upright.
+    type Position = fn(u32, u32) -> (u32, u32);
+
+    #[test]
+    fn orientations() {
+        let (width, height) = (2, 3);
+        let stored = ImageBuffer::from_fn(width, height, |x, y| Luma([(y * width + x) as u8]));
+        let expected: [(u32, Position); 8] = [
+            (1, |x, y| (x, y)),
+            (2, |x, y| (1 - x, y)),
+            (3, |x, y| (1 - x, 2 - y)),
+            (4, |x, y| (x, 2 - y)),
+            (5, |x, y| (y, x)),
+            (6, |x, y| (2 - y, x)),
+            (7, |x, y| (2 - y, 1 - x)),
+            (8, |x, y| (y, 1 - x)),
+        ];
+
+        for &(orientation, get_position) in expected.iter() {
+            let upright = apply_orientation(DynamicImage::ImageLuma8(stored.clone()), orientation);
+            let upright = upright.to_luma();
+            let turned = orientation >= 5;
+            let dimensions = if turned {
+                (height, width)
+            } else {
+                (width, height)
+            };
+            assert_eq!(
+                upright.dimensions(),
+                dimensions,
+                "orientation {}",
+                orientation
+            );
+            for (x, y, pixel) in stored.enumerate_pixels() {
+                let (i, j) = get_position(x, y);
+                assert_eq!(
+                    upright.get_pixel(i, j),
+                    pixel,
+                    "orientation {}",
+                    orientation
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_orientation_is_ignored() {
+        let stored = ImageBuffer::from_fn(2, 3, |x, y| Luma([(y * 2 + x) as u8]));
+        for &orientation in &[0, 9] {
+            let img = apply_orientation(DynamicImage::ImageLuma8(stored.clone()), orientation);
+            let img = img.to_luma();
+            assert_eq!(img.dimensions(), stored.dimensions());
+            assert_eq!(img.into_raw(), stored.clone().into_raw());
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::MatchData;
+
+/// How far along a stage is, with an estimate of the time it has left.
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+    pub eta: Option<Duration>,
+}
+
+impl Progress {
+    pub fn get_fraction(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.done as f64 / self.total as f64
+        }
+    }
+}
+
+/// Receives progress events while a library is scanned and a mosaic is
+/// generated. Events may arrive from several threads at once. Every method
+/// does nothing by default.
+pub trait ProgressSink: Sync {
+    /// Another file in the library folder has been read, `loaded` of those
+    /// so far being usable pictures.
+    fn library_scanned(&self, _loaded: usize, _progress: Progress) {}
+
+    /// The target has been divided into `cells` cells.
+    fn layout_done(&self, _cells: usize) {}
+
+    /// A cell has been matched and its tile rendered.
+    fn cell_matched(&self, _match_data: &MatchData, _progress: Progress) {}
+
+    /// The finished mosaic has been saved to `path`.
+    fn render_written(&self, _path: &Path) {}
+}
+
+/// A `ProgressSink` that ignores every event.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {}
+
+/// Counts steps completed on any thread and estimates the time left from
+/// the average time taken per step so far.
+pub struct ProgressCounter {
+    start: Instant,
+    total: usize,
+    done: AtomicUsize,
+}
+
+impl ProgressCounter {
+    pub fn new(total: usize) -> Self {
+        ProgressCounter {
+            start: Instant::now(),
+            total,
+            done: AtomicUsize::new(0),
+        }
+    }
+
+    /// Records one more finished step.
+    pub fn step(&self) -> Progress {
+        let done = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+        let remaining = self.total.saturating_sub(done) as u32;
+        let eta = self.start.elapsed() / done as u32 * remaining;
+
+        Progress {
+            done,
+            total: self.total,
+            eta: Some(eta),
+        }
+    }
+}
+
+/// Formats a duration as e.g. `1h 02m`, `3m 07s` or `12s`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60)
+    } else if seconds >= 60 {
+        format!("{}m {:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
@@ -0,0 +1,336 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use image::{ImageBuffer, Rgb};
+use rayon::prelude::*;
+
+use crate::{
+    format_config, get_cell, get_cell_tile, parse_config, Library, MatchData, MosaicConfig,
+    Transform,
+};
+
+/// The photo placed in one cell of a saved mosaic.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProjectCell {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub path: PathBuf,
+    pub transform: Transform,
+}
+
+/// Everything needed to reopen a mosaic: where its library and target came
+/// from, the parameters it was made with and the photo chosen for each cell,
+/// so it can be rendered again without matching.
+#[derive(Clone, Debug)]
+pub struct Project {
+    pub library_dirs: Vec<PathBuf>,
+    pub thumb_size: u32,
+    pub excluded: Vec<PathBuf>,
+    pub pinned: Vec<PathBuf>,
+    pub target: PathBuf,
+    /// The mask the cells were laid out in, if there was one.
+    pub mask: Option<PathBuf>,
+    /// Where the rendered mosaic was saved, if it was.
+    pub output: Option<PathBuf>,
+    pub config: MosaicConfig,
+    pub cells: Vec<ProjectCell>,
+}
+
+impl Project {
+    /// Records a mosaic made from `library` and the target at `target`.
+    pub fn new(
+        library: &Library,
+        target: &Path,
+        config: &MosaicConfig,
+        match_data: &[MatchData],
+    ) -> Self {
+        let mut excluded: Vec<PathBuf> = library.excluded.iter().cloned().collect();
+        excluded.sort();
+        let cells = match_data
+            .iter()
+            .map(|m| ProjectCell {
+                x: m.x,
+                y: m.y,
+                width: m.tile.width(),
+                height: m.tile.height(),
+                path: m.path.clone(),
+                transform: m.transform,
+            })
+            .collect();
+
+        Project {
+            library_dirs: library.dirs.clone(),
+            thumb_size: library.thumb_size,
+            excluded,
+            pinned: library.pinned.clone(),
+            target: target.to_path_buf(),
+            mask: None,
+            output: None,
+            config: config.clone(),
+            cells,
+        }
+    }
+
+    /// Applies the project's exclusions and pins to a freshly loaded library.
+    pub fn restore_library(&self, library: &mut Library) {
+        for path in self.excluded.iter() {
+            library.exclude(path.clone());
+        }
+        for path in self.pinned.iter() {
+            library.pin(path.clone());
+        }
+    }
+}
+
+/// Writes `project` as `key = value` lines, the parameters being written by
+/// `format_config` and every cell as `cell = x y width height transform path`.
+pub fn format_project(project: &Project) -> String {
+    let mut text = String::new();
+    for dir in project.library_dirs.iter() {
+        text += &format!("library = {}\n", dir.display());
+    }
+    text += &format!(
+        "thumb_size = {}\ntarget = {}\n",
+        project.thumb_size,
+        project.target.display()
+    );
+    if let Some(mask) = &project.mask {
+        text += &format!("mask = {}\n", mask.display());
+    }
+    if let Some(output) = &project.output {
+        text += &format!("output = {}\n", output.display());
+    }
+    text += &format_config(&project.config);
+    for path in project.excluded.iter() {
+        text += &format!("exclude = {}\n", path.display());
+    }
+    for path in project.pinned.iter() {
+        text += &format!("pin = {}\n", path.display());
+    }
+    for cell in project.cells.iter() {
+        text += &format!(
+            "cell = {} {} {} {} {} {}\n",
+            cell.x,
+            cell.y,
+            cell.width,
+            cell.height,
+            cell.transform.name(),
+            cell.path.display()
+        );
+    }
+
+    text
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn parse_cell(value: &str) -> Option<ProjectCell> {
+    // The path comes last so that it may contain spaces.
+    let mut fields = value.splitn(6, ' ');
+    Some(ProjectCell {
+        x: fields.next()?.parse().ok()?,
+        y: fields.next()?.parse().ok()?,
+        width: fields.next()?.parse().ok()?,
+        height: fields.next()?.parse().ok()?,
+        transform: Transform::from_name(fields.next()?)?,
+        path: PathBuf::from(fields.next()?),
+    })
+}
+
+/// Reads a project written by `format_project`. Unlike the parameters, a
+/// missing library or target or a malformed cell is an error, as the mosaic
+/// couldn't be rendered again.
+pub fn parse_project(text: &str) -> io::Result<Project> {
+    let mut library_dirs = Vec::new();
+    let mut thumb_size = None;
+    let mut target = None;
+    let mut mask = None;
+    let mut output = None;
+    let mut excluded = Vec::new();
+    let mut pinned = Vec::new();
+    let mut cells = Vec::new();
+    for line in text.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        match key {
+            "library" => library_dirs.push(PathBuf::from(value)),
+            "thumb_size" => {
+                let size = value.parse();
+                thumb_size =
+                    Some(size.map_err(|_| invalid_data(format!("bad thumb_size {}", value)))?);
+            }
+            "target" => target = Some(PathBuf::from(value)),
+            "mask" => mask = Some(PathBuf::from(value)),
+            "output" => output = Some(PathBuf::from(value)),
+            "exclude" => excluded.push(PathBuf::from(value)),
+            "pin" => pinned.push(PathBuf::from(value)),
+            "cell" => {
+                let cell = parse_cell(value);
+                cells.push(cell.ok_or_else(|| invalid_data(format!("bad cell {}", value)))?);
+            }
+            _ => {}
+        }
+    }
+
+    if library_dirs.is_empty() {
+        return Err(invalid_data("no library".to_string()));
+    }
+
+    Ok(Project {
+        library_dirs,
+        thumb_size: thumb_size.ok_or_else(|| invalid_data("no thumb_size".to_string()))?,
+        excluded,
+        pinned,
+        target: target.ok_or_else(|| invalid_data("no target".to_string()))?,
+        mask,
+        output,
+        config: parse_config(text),
+        cells,
+    })
+}
+
+pub fn save_project(project: &Project, path: &Path) -> io::Result<()> {
+    fs::write(path, format_project(project))
+}
+
+pub fn load_project(path: &Path) -> io::Result<Project> {
+    parse_project(&fs::read_to_string(path)?)
+}
+
+/// Loads the tile of every cell of `project` without any matching, colour
+/// correcting it against `input`, the target scaled by `get_scaled_input`.
+pub fn get_project_match_data(
+    input: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    project: &Project,
+) -> image::ImageResult<Vec<MatchData>> {
+    project
+        .cells
+        .par_iter()
+        .map(|cell| {
+            let crop = get_cell(input, cell.x, cell.y, cell.width, cell.height);
+            let tile = get_cell_tile(&cell.path, cell.transform, &crop, &project.config)?;
+
+            Ok(MatchData {
+                x: cell.x,
+                y: cell.y,
+                path: cell.path.clone(),
+                transform: cell.transform,
+                tile,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GroutConfig;
+
+    fn get_project() -> Project {
+        let config = MosaicConfig {
+            seed: Some(7),
+            grout: Some(GroutConfig {
+                width: 2,
+                corner_radius: 3,
+                ..GroutConfig::default()
+            }),
+            ..MosaicConfig::default()
+        };
+
+        Project {
+            library_dirs: vec![PathBuf::from("/photos/a"), PathBuf::from("/photos/b c")],
+            thumb_size: 24,
+            excluded: vec![PathBuf::from("/photos/a/x.jpg")],
+            pinned: vec![PathBuf::from("/photos/a/y.jpg")],
+            target: PathBuf::from("/targets/face.png"),
+            mask: Some(PathBuf::from("/targets/heart.png")),
+            output: Some(PathBuf::from("/out/mosaic.png")),
+            config,
+            cells: vec![
+                ProjectCell {
+                    x: 0,
+                    y: 0,
+                    width: 120,
+                    height: 150,
+                    path: PathBuf::from("/photos/a/y.jpg"),
+                    transform: Transform::Identity,
+                },
+                ProjectCell {
+                    x: 120,
+                    y: 0,
+                    width: 130,
+                    height: 150,
+                    path: PathBuf::from("/photos/b c/with = sign.jpg"),
+                    transform: Transform::Rotate270,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let project = get_project();
+        let text = format_project(&project);
+        let parsed = parse_project(&text).unwrap();
+
+        assert_eq!(parsed.library_dirs, project.library_dirs);
+        assert_eq!(parsed.thumb_size, project.thumb_size);
+        assert_eq!(parsed.excluded, project.excluded);
+        assert_eq!(parsed.pinned, project.pinned);
+        assert_eq!(parsed.target, project.target);
+        assert_eq!(parsed.mask, project.mask);
+        assert_eq!(parsed.output, project.output);
+        assert_eq!(parsed.config.seed, Some(7));
+        assert_eq!(parsed.config.grout, project.config.grout);
+        assert_eq!(parsed.cells, project.cells);
+        assert_eq!(format_project(&parsed), text);
+    }
+
+    #[test]
+    fn round_trip_without_mask_or_output() {
+        let project = Project {
+            mask: None,
+            output: None,
+            ..get_project()
+        };
+        let parsed = parse_project(&format_project(&project)).unwrap();
+        assert_eq!(parsed.mask, None);
+        assert_eq!(parsed.output, None);
+    }
+
+    #[test]
+    fn malformed_cell() {
+        let text = format_project(&get_project()) + "cell = 0 0 ten 10 identity /photos/a/z.jpg\n";
+        let err = parse_project(&text).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let text = format_project(&get_project()) + "cell = 0 0 10 10 sideways /photos/a/z.jpg\n";
+        assert!(parse_project(&text).is_err());
+
+        let text = format_project(&get_project()) + "cell = 0 0 10 10 identity\n";
+        assert!(parse_project(&text).is_err());
+    }
+
+    #[test]
+    fn missing_required_keys() {
+        let text = format_project(&get_project());
+        for key in ["library", "thumb_size", "target"] {
+            let without: String = text
+                .lines()
+                .filter(|line| !line.starts_with(key))
+                .map(|line| line.to_string() + "\n")
+                .collect();
+            assert!(parse_project(&without).is_err(), "no {}", key);
+        }
+
+        let text = text.replace("thumb_size = 24", "thumb_size = big");
+        assert!(parse_project(&text).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use image::Rgb;
+
+use crate::{CascadeConfig, GroutConfig, Layout, MosaicConfig};
+
+/// Writes `colour` as a `#rrggbb` hex code.
+pub fn format_colour(colour: Rgb<u8>) -> String {
+    let [r, g, b] = colour.data;
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Reads a colour written as a hex code, with or without the `#`.
+pub fn parse_colour(text: &str) -> Option<Rgb<u8>> {
+    let hex = text.trim_start_matches('#');
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+/// Writes the parameters of `config` as `key = value` lines, one per line,
+/// leaving out those that aren't set.
+pub fn format_config(config: &MosaicConfig) -> String {
+    let mut lines = vec![
+        format!("transforms = {}", config.transforms),
+        format!("cascade = {}", config.cascade.is_some()),
+        format!("min_tile = {}", config.min_tile),
+        format!("max_tile = {}", config.max_tile),
+        format!("layout = {}", config.layout.name()),
+        format!("colour_correction = {}", config.colour_correction),
+        format!("output_scale = {}", config.output_scale),
+        format!("mask_threshold = {}", config.mask_threshold),
+        format!("background = {}", format_colour(config.background)),
+        format!("rgba_output = {}", config.rgba_output),
+    ];
+    if let Some(reuse_limit) = config.reuse_limit {
+        lines.push(format!("reuse_limit = {}", reuse_limit));
+    }
+    if let Some(seed) = config.seed {
+        lines.push(format!("seed = {}", seed));
+    }
+    if let Some(grout) = &config.grout {
+        lines.push(format!("grout_width = {}", grout.width));
+        lines.push(format!("grout_colour = {}", format_colour(grout.colour)));
+        lines.push(format!("corner_radius = {}", grout.corner_radius));
+        lines.push(format!("shadow = {}", grout.shadow));
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Reads parameters written by `format_config`. Anything missing, unknown or
+/// malformed is left as it is in `MosaicConfig::default()`.
+pub fn parse_config(text: &str) -> MosaicConfig {
+    let mut config = MosaicConfig::default();
+    for line in text.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        match key {
+            "transforms" => {
+                config.transforms = value.parse().unwrap_or(config.transforms);
+            }
+            "cascade" if value == "true" => config.cascade = Some(CascadeConfig::default()),
+            "min_tile" => config.min_tile = value.parse().unwrap_or(config.min_tile),
+            "max_tile" => config.max_tile = value.parse().unwrap_or(config.max_tile),
+            "layout" => config.layout = Layout::from_name(value).unwrap_or(config.layout),
+            "reuse_limit" => config.reuse_limit = value.parse().ok().filter(|&limit| limit > 0),
+            "colour_correction" => {
+                config.colour_correction = value.parse().unwrap_or(config.colour_correction);
+            }
+            "output_scale" => {
+                config.output_scale = value.parse().unwrap_or(config.output_scale);
+            }
+            "seed" => config.seed = value.parse().ok(),
+            "mask_threshold" => {
+                config.mask_threshold = value.parse().unwrap_or(config.mask_threshold);
+            }
+            "background" => config.background = parse_colour(value).unwrap_or(config.background),
+            "rgba_output" => {
+                config.rgba_output = value.parse().unwrap_or(config.rgba_output);
+            }
+            "grout_width" => {
+                let grout = config.grout.get_or_insert_with(GroutConfig::default);
+                grout.width = value.parse().unwrap_or(grout.width);
+            }
+            "grout_colour" => {
+                let grout = config.grout.get_or_insert_with(GroutConfig::default);
+                grout.colour = parse_colour(value).unwrap_or(grout.colour);
+            }
+            "corner_radius" => {
+                let grout = config.grout.get_or_insert_with(GroutConfig::default);
+                grout.corner_radius = value.parse().unwrap_or(grout.corner_radius);
+            }
+            "shadow" => {
+                let grout = config.grout.get_or_insert_with(GroutConfig::default);
+                grout.shadow = value.parse().unwrap_or(grout.shadow);
+            }
+            _ => {}
+        }
+    }
+
+    config
+}
+
+/// Where the parameters last used are kept between sessions, under
+/// `$XDG_CONFIG_HOME` or else `~/.config`.
+pub fn get_settings_path() -> Option<PathBuf> {
+    let mut path = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut home = PathBuf::from(env::var_os("HOME")?);
+            home.push(".config");
+            home
+        }
+    };
+    path.push("photo-mosaic");
+    path.push("settings");
+    Some(path)
+}
+
+/// Where files made for the user rather than by them, such as text targets,
+/// are kept, under `$XDG_CACHE_HOME` or else `~/.cache`.
+pub fn get_cache_dir() -> Option<PathBuf> {
+    let mut path = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut home = PathBuf::from(env::var_os("HOME")?);
+            home.push(".cache");
+            home
+        }
+    };
+    path.push("photo-mosaic");
+    Some(path)
+}
+
+/// Returns the parameters saved by `save_settings`, or the defaults if there
+/// are none.
+pub fn load_settings() -> MosaicConfig {
+    match get_settings_path().and_then(|path| fs::read_to_string(path).ok()) {
+        Some(text) => parse_config(&text),
+        None => MosaicConfig::default(),
+    }
+}
+
+pub fn save_settings(config: &MosaicConfig) -> io::Result<()> {
+    let path = get_settings_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, format_config(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colour_round_trip() {
+        let colour = Rgb([255, 128, 0]);
+        assert_eq!(format_colour(colour), "#ff8000");
+        assert_eq!(parse_colour("#ff8000"), Some(colour));
+        assert_eq!(parse_colour("FF8000"), Some(colour));
+    }
+
+    #[test]
+    fn bad_colours() {
+        assert_eq!(parse_colour(""), None);
+        assert_eq!(parse_colour("#fff"), None);
+        assert_eq!(parse_colour("#ff80001"), None);
+        assert_eq!(parse_colour("#gg8000"), None);
+        assert_eq!(parse_colour("#+f+f+f"), None);
+        assert_eq!(parse_colour("éééé"), None);
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let config = MosaicConfig {
+            transforms: true,
+            cascade: Some(CascadeConfig::default()),
+            min_tile: 40,
+            max_tile: 90,
+            layout: Layout::Grid,
+            reuse_limit: Some(3),
+            colour_correction: 0.25,
+            output_scale: 2.5,
+            seed: Some(42),
+            mask_threshold: 0.5,
+            background: Rgb([1, 2, 3]),
+            rgba_output: true,
+            grout: Some(GroutConfig {
+                width: 6,
+                colour: Rgb([10, 20, 30]),
+                corner_radius: 5,
+                shadow: 2,
+            }),
+            ..MosaicConfig::default()
+        };
+        let text = format_config(&config);
+        let parsed = parse_config(&text);
+
+        assert!(parsed.transforms);
+        assert!(parsed.cascade.is_some());
+        assert_eq!((parsed.min_tile, parsed.max_tile), (40, 90));
+        assert_eq!(parsed.layout, Layout::Grid);
+        assert_eq!(parsed.reuse_limit, Some(3));
+        assert_eq!(parsed.colour_correction, 0.25);
+        assert_eq!(parsed.output_scale, 2.5);
+        assert_eq!(parsed.seed, Some(42));
+        assert_eq!(parsed.mask_threshold, 0.5);
+        assert_eq!(parsed.background, Rgb([1, 2, 3]));
+        assert!(parsed.rgba_output);
+        assert_eq!(parsed.grout, config.grout);
+        assert_eq!(format_config(&parsed), text);
+    }
+
+    #[test]
+    fn default_config_round_trip() {
+        let text = format_config(&MosaicConfig::default());
+        let parsed = parse_config(&text);
+        assert!(parsed.cascade.is_none());
+        assert!(parsed.reuse_limit.is_none());
+        assert!(parsed.seed.is_none());
+        assert!(parsed.grout.is_none());
+        assert_eq!(format_config(&parsed), text);
+    }
+
+    #[test]
+    fn malformed_config_keeps_defaults() {
+        let text = "min_tile = lots\nlayout = hexagons\nbackground = red\n\
+                    colour = blue\nno equals sign here\nmax_tile = 200\n";
+        let config = parse_config(text);
+        let default = MosaicConfig::default();
+        assert_eq!(config.min_tile, default.min_tile);
+        assert_eq!(config.layout, default.layout);
+        assert_eq!(config.background, default.background);
+        assert_eq!(config.max_tile, 200);
+    }
+
+    #[test]
+    fn zero_reuse_limit_is_no_limit() {
+        assert_eq!(parse_config("reuse_limit = 0\n").reuse_limit, None);
+        assert_eq!(parse_config("reuse_limit = 2\n").reuse_limit, Some(2));
+    }
+
+    #[test]
+    fn partial_grout_fills_in_defaults() {
+        let config = parse_config("shadow = 3\n");
+        let grout = config.grout.unwrap();
+        assert_eq!(grout.shadow, 3);
+        assert_eq!(grout.width, GroutConfig::default().width);
+    }
+}
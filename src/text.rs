@@ -0,0 +1,200 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use image::{ImageBuffer, Luma, Rgb, Rgba};
+use rusttype::{point, Font, Scale};
+
+use crate::{fill_tile, Alpha};
+
+/// Reads a TrueType or OpenType font.
+pub fn open_font(path: &Path) -> io::Result<Font<'static>> {
+    Font::try_from_vec(fs::read(path)?).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a font", path.display()),
+        )
+    })
+}
+
+/// Draws `text` in `font`, `size` pixels from one line to the next, as a
+/// mask set inside the glyphs. Lines are split at newlines and centred, with
+/// a margin of a quarter of `size` all round.
+pub fn get_text_mask(font: &Font, text: &str, size: f32) -> Alpha {
+    let scale = Scale::uniform(size);
+    let metrics = font.v_metrics(scale);
+    let line_height = metrics.ascent - metrics.descent + metrics.line_gap;
+    let margin = (size / 4.0).ceil() as i32;
+
+    let lines: Vec<Vec<_>> = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let baseline = point(0.0, metrics.ascent + i as f32 * line_height);
+            font.layout(line, scale, baseline).collect()
+        })
+        .collect();
+    let line_widths: Vec<i32> = lines
+        .iter()
+        .map(|glyphs| {
+            glyphs
+                .iter()
+                .filter_map(|glyph| glyph.pixel_bounding_box())
+                .map(|bounds| bounds.max.x)
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let text_width = line_widths.iter().copied().max().unwrap_or(0);
+    let text_height = (lines.len().max(1) as f32 * line_height).ceil() as i32;
+
+    let width = (text_width + 2 * margin).max(1) as u32;
+    let height = (text_height + 2 * margin).max(1) as u32;
+    let mut mask: Alpha = ImageBuffer::new(width, height);
+    for (glyphs, line_width) in lines.iter().zip(line_widths) {
+        let offset = margin + (text_width - line_width) / 2;
+        for glyph in glyphs.iter() {
+            let bounds = match glyph.pixel_bounding_box() {
+                Some(bounds) => bounds,
+                None => continue,
+            };
+            glyph.draw(|x, y, coverage| {
+                let x = offset + bounds.min.x + x as i32;
+                let y = margin + bounds.min.y + y as i32;
+                if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+                    let value = mask.get_pixel(x as u32, y as u32).data[0];
+                    let value = value.max((coverage * 255.0).round() as u8);
+                    mask.put_pixel(x as u32, y as u32, Luma([value]));
+                }
+            });
+        }
+    }
+
+    mask
+}
+
+/// What a text mosaic is made to look like inside its glyphs.
+#[derive(Clone, Debug)]
+pub enum TextFill {
+    Flat(Rgb<u8>),
+    /// From the first colour at the top to the second at the bottom.
+    Gradient(Rgb<u8>, Rgb<u8>),
+    /// A photo cropped and scaled to cover the whole text.
+    Image(ImageBuffer<Rgb<u8>, Vec<u8>>),
+}
+
+/// Makes a target of the text in `mask`, see `get_text_mask`, coloured by
+/// `fill` and transparent outside the glyphs, so that tiles are only placed
+/// inside them.
+pub fn get_text_target(mask: &Alpha, fill: &TextFill) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let (width, height) = mask.dimensions();
+    let image = match fill {
+        TextFill::Image(img) => Some(fill_tile(img, width, height)),
+        _ => None,
+    };
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let colour = match fill {
+            TextFill::Flat(colour) => *colour,
+            TextFill::Gradient(top, bottom) => {
+                let t = y as f64 / (height - 1).max(1) as f64;
+                let mut colour = *top;
+                for (value, &end) in colour.data.iter_mut().zip(bottom.data.iter()) {
+                    *value = (*value as f64 * (1.0 - t) + end as f64 * t).round() as u8;
+                }
+                colour
+            }
+            TextFill::Image(_) => *image.as_ref().unwrap().get_pixel(x, y),
+        };
+        let [r, g, b] = colour.data;
+        Rgba([r, g, b, mask.get_pixel(x, y).data[0]])
+    })
+}
+
+/// Where to save a new text target in `dir`: named by when it was made so
+/// earlier ones are kept for the projects that use them, and numbered if
+/// others were made in the same second.
+pub fn get_text_target_path(dir: &Path) -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let mut path = dir.join(format!("text-{}.png", secs));
+    let mut count = 1;
+    while path.exists() {
+        count += 1;
+        path = dir.join(format!("text-{}-{}.png", secs, count));
+    }
+    path
+}
+
+/// Why `write_text_target` failed.
+#[derive(Debug)]
+pub enum TextTargetError {
+    /// The font couldn't be read.
+    Font(PathBuf, io::Error),
+    /// The target couldn't be saved.
+    Save(PathBuf, io::Error),
+}
+
+impl fmt::Display for TextTargetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TextTargetError::Font(path, err) => {
+                write!(f, "Couldn't read {}: {}", path.display(), err)
+            }
+            TextTargetError::Save(path, err) => {
+                write!(f, "Couldn't save {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl Error for TextTargetError {}
+
+/// Draws `text` in the font at `font_path`, see `get_text_mask`, and saves
+/// it as a target filled with `fill` at `path`, creating its directory if
+/// need be.
+pub fn write_text_target(
+    text: &str,
+    font_path: &Path,
+    size: f32,
+    fill: &TextFill,
+    path: &Path,
+) -> Result<(), TextTargetError> {
+    let font =
+        open_font(font_path).map_err(|err| TextTargetError::Font(font_path.to_path_buf(), err))?;
+    let target = get_text_target(&get_text_mask(&font, text, size), fill);
+
+    let save = || {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        target.save(path)
+    };
+    save().map_err(|err| TextTargetError::Save(path.to_path_buf(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn text_target_paths_are_new() {
+        let dir = env::temp_dir().join(format!("mosaic-text-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut paths = Vec::new();
+        for _ in 0..3 {
+            let path = get_text_target_path(&dir);
+            assert!(!paths.contains(&path));
+            fs::write(&path, "").unwrap();
+            paths.push(path);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}